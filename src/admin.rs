@@ -1,12 +1,13 @@
 use worker::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use crate::{error, read_config, attach_origin_to_header};
 
 use crate::constants::*;
+use crate::apikey::{self, Scope};
 use crate::prompt::Config;
 use serde_json::json;
 
-async fn verify_identity(req: &Request, env: &Env) -> crate::Result<()> {
+pub(crate) async fn verify_identity(req: &Request, env: &Env) -> crate::Result<()> {
     if env.var("DEV_MODE")?.to_string() == "1" {
         console_log!("DEV_MODE is on, skipping identity verification");
         return Ok(());
@@ -59,25 +60,197 @@ async fn verify_identity(req: &Request, env: &Env) -> crate::Result<()> {
     Ok(())
 }
 
+/// Admins, or clients with an API key that may read admin data.
+pub(crate) async fn verify_read_access(
+    req: &Request,
+    ctx: &RouteContext<()>,
+) -> crate::Result<()> {
+    let kv = ctx.kv(KV_BINDING)?;
+    match apikey::authenticate(req, &kv, Scope::AdminRead).await? {
+        Some(_) => Ok(()),
+        None => verify_identity(req, &ctx.env).await,
+    }
+}
+
 async fn set_config(config: &Config, ctx: &RouteContext<()>) -> Result<()> {
     let kv = ctx.kv(KV_BINDING)?;
     kv.put("config", config)?.execute().await?;
     Ok(())
 }
 
-async fn set_config_backup(config: &Config, ctx: &RouteContext<()>) -> Result<()> {
-    let time_string = Date::now().to_string();
+const CONFIG_BACKUP_PREFIX: &str = "config_backup_";
+
+#[derive(Serialize, Deserialize)]
+struct BackupMetadata {
+    /// milliseconds since epoch
+    timestamp: u64,
+    /// bytes of the serialized config
+    size: usize,
+    /// `None` for backups made before configs were versioned
+    #[serde(default)]
+    version: Option<u32>,
+    #[serde(default)]
+    hash: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BackupSummary {
+    key: String,
+    timestamp: u64,
+    size: usize,
+    version: Option<u32>,
+    hash: Option<String>,
+}
+
+async fn set_config_backup(config: &Config, ctx: &RouteContext<()>) -> Result<String> {
+    let now = Date::now();
+    // the date only has seconds, so a save and a restore within one second would collide
+    // without the version, which every save and restore bumps
+    let key = format!(
+        "{}{}_v{}",
+        CONFIG_BACKUP_PREFIX,
+        now.to_string(),
+        config.version
+    );
+    let metadata = BackupMetadata {
+        timestamp: now.as_millis(),
+        size: serde_json::to_string(config)?.len(),
+        version: Some(config.version),
+        hash: Some(config.hash()),
+    };
     let kv = ctx.kv(KV_BINDING)?;
-    kv.put(&format!("config_backup_{}", time_string), config)?
+    kv.put(&key, config)?
+        .metadata(metadata)?
         // expire in 1 year
         .expiration_ttl(60 * 60 * 24 * 365)
         .execute()
         .await?;
-    Ok(())
+    Ok(key)
+}
+
+pub(crate) fn query_param(req: &Request, name: &str) -> crate::Result<String> {
+    Ok(req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == name)
+        .ok_or_else(|| {
+            error::Error::InvalidRequest(format!("Expected query parameter `{}`", name))
+        })?
+        .1
+        .to_string())
+}
+
+async fn read_config_backup(key: &str, ctx: &RouteContext<()>) -> crate::Result<Config> {
+    if !key.starts_with(CONFIG_BACKUP_PREFIX) {
+        return Err(error::Error::InvalidRequest(format!(
+            "{} is not a config backup",
+            key
+        )));
+    }
+    let kv = ctx.kv(KV_BINDING)?;
+    kv.get(key)
+        .json::<Config>()
+        .await?
+        .ok_or_else(|| error::Error::NotFound(format!("No config backup found with key {}", key)))
+}
+
+async fn list_config_backups(ctx: &RouteContext<()>) -> crate::Result<Vec<BackupSummary>> {
+    let kv = ctx.kv(KV_BINDING)?;
+    let mut backups = Vec::new();
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(CONFIG_BACKUP_PREFIX.to_string());
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        for key in page.keys {
+            let metadata = key
+                .metadata
+                .and_then(|m| serde_json::from_value::<BackupMetadata>(m).ok());
+            let summary = match metadata {
+                Some(metadata) => BackupSummary {
+                    key: key.name,
+                    timestamp: metadata.timestamp,
+                    size: metadata.size,
+                    version: metadata.version,
+                    hash: metadata.hash,
+                },
+                None => {
+                    // backups made before metadata was attached: the key holds the date
+                    let date = &key.name[CONFIG_BACKUP_PREFIX.len()..];
+                    let timestamp = Date::new(DateInit::String(date.to_string())).as_millis();
+                    let size = kv.get(&key.name).text().await?.unwrap_or_default().len();
+                    BackupSummary {
+                        key: key.name,
+                        timestamp,
+                        size,
+                        version: None,
+                        hash: None,
+                    }
+                }
+            };
+            backups.push(summary);
+        }
+        if page.list_complete {
+            break;
+        }
+        cursor = page.cursor;
+    }
+    backups.sort_by_key(|b| std::cmp::Reverse(b.timestamp));
+    Ok(backups)
+}
+
+/// A field that differs between two configs, as a JSON pointer.
+#[derive(Serialize, Debug, PartialEq)]
+struct DiffEntry {
+    path: String,
+    live: serde_json::Value,
+    backup: serde_json::Value,
+}
+
+fn diff_json(
+    path: &str,
+    live: &serde_json::Value,
+    backup: &serde_json::Value,
+    diff: &mut Vec<DiffEntry>,
+) {
+    use serde_json::Value;
+    match (live, backup) {
+        (Value::Object(live), Value::Object(backup)) => {
+            let mut keys: Vec<&String> = live.keys().chain(backup.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                diff_json(
+                    &format!("{}/{}", path, key),
+                    live.get(key).unwrap_or(&Value::Null),
+                    backup.get(key).unwrap_or(&Value::Null),
+                    diff,
+                );
+            }
+        }
+        (Value::Array(live), Value::Array(backup)) => {
+            for i in 0..live.len().max(backup.len()) {
+                diff_json(
+                    &format!("{}/{}", path, i),
+                    live.get(i).unwrap_or(&Value::Null),
+                    backup.get(i).unwrap_or(&Value::Null),
+                    diff,
+                );
+            }
+        }
+        (live, backup) if live != backup => diff.push(DiffEntry {
+            path: path.to_string(),
+            live: live.clone(),
+            backup: backup.clone(),
+        }),
+        _ => {}
+    }
 }
 
 pub async fn handle_config_get(req: Request, ctx: RouteContext<()>) -> crate::Result<Response> {
-    verify_identity(&req, &ctx.env).await?;
+    verify_read_access(&req, &ctx).await?;
 
     let config = read_config(&ctx).await?;
     let mut resp = Response::from_json(&config)?;
@@ -88,18 +261,185 @@ pub async fn handle_config_get(req: Request, ctx: RouteContext<()>) -> crate::Re
 pub async fn handle_config_post(mut req: Request, ctx: RouteContext<()>) -> crate::Result<Response> {
     verify_identity(&req, &ctx.env).await?;
 
-    // get old config
-    let old_config = read_config(&ctx).await?;
-    // backup old config
-    set_config_backup(&old_config, &ctx).await?;
+    let body = req.text().await?;
+    let mut config: Config = serde_json::from_str(&body)
+        .map_err(|e| error::Error::InvalidRequest(format!("malformed config: {}", e)))?;
+    let errors = config.validate();
+    if !errors.is_empty() {
+        return Err(error::Error::InvalidConfig(errors));
+    }
+
+    // with `?dry_run=true`, only validate
+    let dry_run = query_param(&req, "dry_run").is_ok_and(|v| v == "true" || v == "1");
+    if !dry_run {
+        // get old config
+        let old_config = read_config(&ctx).await?;
+        // backup old config
+        set_config_backup(&old_config, &ctx).await?;
 
-    let config: Config = req.json().await?;
-    set_config(&config, &ctx).await?;
+        // whatever version the body says, the new config comes after the live one
+        config.version = old_config.version + 1;
+        set_config(&config, &ctx).await?;
+        console_log!("config updated to version {}", config.version);
+    }
 
     let mut resp = Response::from_json(&json!({
         "success": true,
+        "dry_run": dry_run,
+        "version": config.version,
+        "hash": config.hash(),
     }))?;
     attach_origin_to_header(&req, resp.headers_mut())?;
-    console_log!("config updated");
     Ok(resp)
-}
\ No newline at end of file
+}
+
+pub async fn handle_config_backups_list(
+    req: Request,
+    ctx: RouteContext<()>,
+) -> crate::Result<Response> {
+    verify_read_access(&req, &ctx).await?;
+
+    let backups = list_config_backups(&ctx).await?;
+    let mut resp = Response::from_json(&json!({ "backups": backups }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+pub async fn handle_config_backup_get(
+    req: Request,
+    ctx: RouteContext<()>,
+) -> crate::Result<Response> {
+    verify_read_access(&req, &ctx).await?;
+
+    let key = query_param(&req, "key")?;
+    let backup = read_config_backup(&key, &ctx).await?;
+    let mut resp = Response::from_json(&backup)?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+pub async fn handle_config_backup_diff(
+    req: Request,
+    ctx: RouteContext<()>,
+) -> crate::Result<Response> {
+    verify_read_access(&req, &ctx).await?;
+
+    let key = query_param(&req, "key")?;
+    let backup = serde_json::to_value(read_config_backup(&key, &ctx).await?)?;
+    let live = serde_json::to_value(read_config(&ctx).await?)?;
+    let mut diff = Vec::new();
+    diff_json("", &live, &backup, &mut diff);
+
+    let mut resp = Response::from_json(&json!({ "key": key, "diff": diff }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+pub async fn handle_config_backup_restore(
+    req: Request,
+    ctx: RouteContext<()>,
+) -> crate::Result<Response> {
+    verify_identity(&req, &ctx.env).await?;
+
+    let key = query_param(&req, "key")?;
+    let mut backup = read_config_backup(&key, &ctx).await?;
+    let errors = backup.validate();
+    if !errors.is_empty() {
+        return Err(error::Error::InvalidConfig(errors));
+    }
+
+    // the config being replaced becomes a backup itself, so a restore can be undone
+    let old_config = read_config(&ctx).await?;
+    let previous = set_config_backup(&old_config, &ctx).await?;
+    // a restored config is a new version, with the content of an old one
+    backup.version = old_config.version + 1;
+    // the live config is a single KV entry, so it is swapped in one write
+    set_config(&backup, &ctx).await?;
+
+    let mut resp = Response::from_json(&json!({
+        "success": true,
+        "restored": key,
+        "previous": previous,
+        "version": backup.version,
+    }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    console_log!("config restored from {}", key);
+    Ok(resp)
+}
+
+/// The config with `?version=`: the live one, or the backup made when it was replaced.
+pub async fn handle_config_version_get(
+    req: Request,
+    ctx: RouteContext<()>,
+) -> crate::Result<Response> {
+    verify_read_access(&req, &ctx).await?;
+
+    let version = query_param(&req, "version")?
+        .parse::<u32>()
+        .map_err(|_| error::Error::InvalidRequest("`version` must be a number".to_string()))?;
+    let live = read_config(&ctx).await?;
+    let (key, config) = if live.version == version {
+        (None, live)
+    } else {
+        let key = list_config_backups(&ctx)
+            .await?
+            .into_iter()
+            .find(|backup| backup.version == Some(version))
+            .ok_or_else(|| {
+                error::Error::NotFound(format!("No config found with version {}", version))
+            })?
+            .key;
+        let config = read_config_backup(&key, &ctx).await?;
+        (Some(key), config)
+    };
+
+    let mut resp = Response::from_json(&json!({
+        "version": config.version,
+        "hash": config.hash(),
+        "key": key,
+        "config": config,
+    }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_configs() {
+        let live = json!({
+            "prompt": {"model": "gpt-4", "messages": [{"role": "system", "content": "a"}]},
+            "questions": ["x", "y"],
+            "announcement": null,
+        });
+        let backup = json!({
+            "prompt": {"model": "gpt-4", "messages": [{"role": "system", "content": "b"}]},
+            "questions": ["x"],
+            "announcement": "hello",
+        });
+        let mut diff = Vec::new();
+        diff_json("", &live, &backup, &mut diff);
+        assert_eq!(
+            diff,
+            vec![
+                DiffEntry {
+                    path: "/announcement".to_string(),
+                    live: json!(null),
+                    backup: json!("hello"),
+                },
+                DiffEntry {
+                    path: "/prompt/messages/0/content".to_string(),
+                    live: json!("a"),
+                    backup: json!("b"),
+                },
+                DiffEntry {
+                    path: "/questions/1".to_string(),
+                    live: json!("y"),
+                    backup: json!(null),
+                },
+            ]
+        );
+    }
+}
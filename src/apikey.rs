@@ -0,0 +1,252 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use worker::{console_log, kv::KvStore, Request, Response, RouteContext};
+
+use crate::admin::{query_param, verify_identity};
+use crate::attach_origin_to_header;
+use crate::constants::KV_BINDING;
+use crate::error::{Error, Result};
+use crate::id;
+use crate::ratelimit::RateLimitConfig;
+
+/// KV entries are stored under this prefix followed by the SHA-256 of the key.
+const API_KEY_PREFIX: &str = "apikey_";
+/// start of every key, so that leaked keys are easy to recognize
+const KEY_MARKER: &str = "slr_";
+
+/// What a key may be used for.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// ask questions without a captcha
+    #[serde(rename = "chat")]
+    Chat,
+    #[serde(rename = "lookup")]
+    Lookup,
+    /// read-only admin endpoints, such as the config and the logs
+    #[serde(rename = "admin-read")]
+    AdminRead,
+}
+
+/// Limits for one key, which replace the per-IP limits. Global limits still apply.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct KeyRateLimit {
+    pub per_minute: Option<u32>,
+    pub per_day: Option<u32>,
+}
+
+/// An API key as stored in KV. The key itself is only shown once, when it is minted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    /// SHA-256 of the key, in hex
+    pub id: String,
+    /// first characters of the key, to tell keys apart
+    pub hint: String,
+    pub label: String,
+    pub scopes: Vec<Scope>,
+    #[serde(default)]
+    pub rate_limit: KeyRateLimit,
+    /// seconds since epoch
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    #[serde(default)]
+    pub revoked: bool,
+}
+
+impl ApiKey {
+    fn is_active_at(&self, now: i64) -> bool {
+        !self.revoked && self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+
+    /// Name of this key in the rate limiter, in place of an IP.
+    pub fn client(&self) -> String {
+        format!("key_{}", &self.id[..16])
+    }
+
+    /// `global` with the per-IP limits replaced by the limits of this key.
+    pub fn rate_limit_config(&self, global: &RateLimitConfig) -> RateLimitConfig {
+        RateLimitConfig {
+            per_ip_per_minute: self.rate_limit.per_minute,
+            per_ip_per_day: self.rate_limit.per_day,
+            ..global.clone()
+        }
+    }
+}
+
+fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn kv_key(id: &str) -> String {
+    format!("{}{}", API_KEY_PREFIX, id)
+}
+
+/// Key presented as `Authorization: Bearer <key>`, if any.
+fn bearer_token(req: &Request) -> Result<Option<String>> {
+    match req.headers().get("Authorization")? {
+        Some(value) => value
+            .strip_prefix("Bearer ")
+            .map(|key| Some(key.trim().to_string()))
+            .ok_or_else(|| Error::InvalidRequest("expected a Bearer token".to_string())),
+        None => Ok(None),
+    }
+}
+
+/// The key presented by `req`, or `None` if there is none. A key that is unknown, revoked,
+/// expired or lacks `scope` is refused.
+pub async fn authenticate(req: &Request, kv: &KvStore, scope: Scope) -> Result<Option<ApiKey>> {
+    let key = match bearer_token(req)? {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    let api_key = kv
+        .get(&kv_key(&hash_key(&key)))
+        .json::<ApiKey>()
+        .await?
+        .ok_or(Error::Forbidden)?;
+    if !api_key.is_active_at(id::get_utc_timestamp_sec()) || !api_key.scopes.contains(&scope) {
+        console_log!("API key {} refused for {:?}", api_key.hint, scope);
+        return Err(Error::Forbidden);
+    }
+    Ok(Some(api_key))
+}
+
+#[derive(Deserialize)]
+struct MintRequest {
+    label: String,
+    scopes: Vec<Scope>,
+    #[serde(default)]
+    rate_limit: KeyRateLimit,
+    /// never expires if omitted
+    expires_in_days: Option<u32>,
+}
+
+pub async fn handle_api_key_mint(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    verify_identity(&req, &ctx.env).await?;
+
+    let mint: MintRequest = serde_json::from_str(&req.text().await?)
+        .map_err(|e| Error::InvalidRequest(format!("malformed API key request: {}", e)))?;
+    if mint.label.trim().is_empty() || mint.scopes.is_empty() {
+        return Err(Error::InvalidRequest(
+            "an API key needs a label and at least one scope".to_string(),
+        ));
+    }
+
+    let key = format!("{}{}", KEY_MARKER, hex::encode(rand::random::<[u8; 24]>()));
+    let now = id::get_utc_timestamp_sec();
+    let api_key = ApiKey {
+        id: hash_key(&key),
+        hint: key[..KEY_MARKER.len() + 4].to_string(),
+        label: mint.label,
+        scopes: mint.scopes,
+        rate_limit: mint.rate_limit,
+        created_at: now,
+        expires_at: mint
+            .expires_in_days
+            .map(|days| now + days as i64 * 60 * 60 * 24),
+        revoked: false,
+    };
+    let kv = ctx.kv(KV_BINDING)?;
+    kv.put(&kv_key(&api_key.id), &api_key)?.execute().await?;
+    console_log!("API key {} minted for {}", api_key.hint, api_key.label);
+
+    let mut resp = Response::from_json(&json!({
+        "key": key,
+        "api_key": api_key,
+    }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+pub async fn handle_api_keys_list(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    verify_identity(&req, &ctx.env).await?;
+
+    let kv = ctx.kv(KV_BINDING)?;
+    let mut keys = Vec::new();
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(API_KEY_PREFIX.to_string());
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        for key in page.keys {
+            if let Some(api_key) = kv.get(&key.name).json::<ApiKey>().await? {
+                keys.push(api_key);
+            }
+        }
+        if page.list_complete {
+            break;
+        }
+        cursor = page.cursor;
+    }
+    keys.sort_by_key(|k| std::cmp::Reverse(k.created_at));
+
+    let mut resp = Response::from_json(&json!({ "keys": keys }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+/// Revoked keys are kept, so that the list still shows who had access.
+pub async fn handle_api_key_revoke(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    verify_identity(&req, &ctx.env).await?;
+
+    let id = query_param(&req, "id")?;
+    let kv = ctx.kv(KV_BINDING)?;
+    let mut api_key = kv
+        .get(&kv_key(&id))
+        .json::<ApiKey>()
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("No API key found with id {}", id)))?;
+    api_key.revoked = true;
+    kv.put(&kv_key(&id), &api_key)?.execute().await?;
+    console_log!("API key {} revoked", api_key.hint);
+
+    let mut resp = Response::from_json(&json!({ "success": true, "api_key": api_key }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_key() -> ApiKey {
+        serde_json::from_value(json!({
+            "id": hash_key("slr_0123456789abcdef"),
+            "hint": "slr_0123",
+            "label": "slack bot",
+            "scopes": ["chat", "admin-read"],
+            "rate_limit": {"per_minute": 10},
+            "created_at": 1_000,
+            "expires_at": 2_000,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn key_state() {
+        let mut key = api_key();
+        assert_eq!(key.id.len(), 64);
+        assert!(key.is_active_at(1_999));
+        assert!(!key.is_active_at(2_000));
+        key.expires_at = None;
+        assert!(key.is_active_at(1_000_000));
+        key.revoked = true;
+        assert!(!key.is_active_at(1_000));
+    }
+
+    #[test]
+    fn key_rate_limit() {
+        let global = RateLimitConfig {
+            per_ip_per_minute: Some(1),
+            per_ip_per_day: Some(5),
+            global_requests_per_day: Some(100),
+            global_tokens_per_day: None,
+        };
+        let config = api_key().rate_limit_config(&global);
+        assert_eq!(config.per_ip_per_minute, Some(10));
+        assert_eq!(config.per_ip_per_day, None);
+        assert_eq!(config.global_requests_per_day, Some(100));
+    }
+}
@@ -0,0 +1,291 @@
+//! Answers to first questions, kept for a while so that questions asked over and over, like the
+//! hint `questions`, aren't paid for every time.
+//!
+//! Entries are keyed by the config version and hash and the normalized question. Every save or
+//! restore of the config bumps the version, even if the content is the same, so it starts with
+//! an empty cache; the answers cached under earlier versions expire with their TTL.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use worker::{console_log, kv::KvStore, Request, Response, RouteContext};
+
+use crate::admin::{query_param, verify_identity};
+use crate::attach_origin_to_header;
+use crate::constants::KV_BINDING;
+use crate::error::{Error, Result};
+use crate::prompt::Config;
+use crate::stream_parser::FinishReason;
+use crate::LogKvEntry;
+
+const CACHE_PREFIX: &str = "answer_cache_";
+/// every key deleted is a KV subrequest, of which an invocation gets about 1000
+const DEFAULT_INVALIDATE_PAGE_SIZE: u64 = 500;
+const MAX_INVALIDATE_PAGE_SIZE: u64 = 900;
+/// longest allowed `ttl_hours`
+pub const MAX_TTL_HOURS: u32 = 24 * 30;
+/// longest piece of a word replayed as one delta, in characters
+const REPLAY_PIECE_CHARS: usize = 4;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// how long an answer is served from the cache
+    pub ttl_hours: u32,
+    /// only cache answers to the hint `questions`
+    pub hints_only: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_hours: 24,
+            hints_only: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CachedAnswer {
+    pub answer: String,
+    /// model that answered
+    pub model: String,
+    /// seconds since epoch
+    pub created_at: i64,
+}
+
+/// The question as compared for caching: lowercase, with whitespace collapsed and without
+/// closing punctuation.
+pub fn normalize(question: &str) -> String {
+    question
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_end_matches(|c: char| "?!.？！。".contains(c) || c.is_whitespace())
+        .to_string()
+}
+
+/// KV key of the answer to `question` under `config`, which hashes to `config_hash`, and its
+/// prompt `variant`, if the question may be answered from the cache. Only the first question
+/// of a conversation is, since later ones depend on what was said before.
+pub fn cache_key(
+    config: &Config,
+    config_hash: &str,
+    variant: Option<&str>,
+    question: &str,
+    turn: u32,
+) -> Option<String> {
+    let cache = config.cache.as_ref()?;
+    let normalized = normalize(question);
+    if turn > 0
+        || normalized.is_empty()
+        || (cache.hints_only && !config.questions.iter().any(|q| normalize(q) == normalized))
+    {
+        return None;
+    }
+    let hash = match variant {
+        Some(variant) => Sha256::digest(format!("{}:{}", variant, normalized).as_bytes()),
+        None => Sha256::digest(normalized.as_bytes()),
+    };
+    // the hash tells apart configs never saved through the API, which are all version 0
+    Some(format!(
+        "{}v{}_{}_{}",
+        CACHE_PREFIX,
+        config.version,
+        config_hash,
+        hex::encode(&hash[..16])
+    ))
+}
+
+/// Whether the answer logged as `entry` may be cached: a complete, freshly generated answer
+/// that didn't call tools, whose output, like the time or the latest posts, goes stale.
+pub fn is_cacheable(entry: &LogKvEntry) -> bool {
+    !entry.cached && entry.finish_reason == Some(FinishReason::Stop) && entry.tool_calls.is_empty()
+}
+
+pub async fn get(kv: &KvStore, key: &str) -> Result<Option<CachedAnswer>> {
+    Ok(kv.get(key).json::<CachedAnswer>().await?)
+}
+
+pub async fn put(kv: &KvStore, key: &str, answer: &CachedAnswer, ttl_hours: u32) -> Result<()> {
+    kv.put(key, answer)?
+        .expiration_ttl(ttl_hours as u64 * 60 * 60)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Delete up to `limit` cached answers, starting at `cursor`. Returns how many were deleted and
+/// the cursor of the rest, `None` once there are none left.
+pub async fn invalidate(
+    kv: &KvStore,
+    cursor: Option<String>,
+    limit: u64,
+) -> Result<(usize, Option<String>)> {
+    let mut list = kv.list().prefix(CACHE_PREFIX.to_string()).limit(limit);
+    if let Some(cursor) = cursor {
+        list = list.cursor(cursor);
+    }
+    let page = list.execute().await?;
+    for key in &page.keys {
+        kv.delete(&key.name).await?;
+    }
+    let cursor = if page.list_complete {
+        None
+    } else {
+        page.cursor
+    };
+    Ok((page.keys.len(), cursor))
+}
+
+/// Split an answer into deltas of about a word, as a model would stream it. Runs without
+/// spaces, like Chinese, are cut every few characters.
+pub fn replay_chunks(answer: &str) -> Vec<String> {
+    let mut chunks = Vec::new();
+    for word in answer.split_inclusive(char::is_whitespace) {
+        if word.trim_end().chars().count() <= REPLAY_PIECE_CHARS {
+            chunks.push(word.to_string());
+        } else {
+            let chars: Vec<char> = word.chars().collect();
+            chunks.extend(
+                chars
+                    .chunks(REPLAY_PIECE_CHARS)
+                    .map(|piece| piece.iter().collect::<String>()),
+            );
+        }
+    }
+    chunks
+}
+
+/// Drop cached answers, e.g. after editing a document the prompt depends on. Deletes up to
+/// `?limit=` at a time; call again with the returned `cursor` until it is `null`.
+pub async fn handle_cache_invalidate(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    verify_identity(&req, &ctx.env).await?;
+
+    let limit = match query_param(&req, "limit") {
+        Ok(limit) => limit
+            .parse::<u64>()
+            .map_err(|_| Error::InvalidRequest("`limit` must be a number".to_string()))?
+            .clamp(1, MAX_INVALIDATE_PAGE_SIZE),
+        Err(_) => DEFAULT_INVALIDATE_PAGE_SIZE,
+    };
+    let cursor = query_param(&req, "cursor").ok();
+    let (deleted, cursor) = invalidate(&ctx.kv(KV_BINDING)?, cursor, limit).await?;
+    console_log!("{} cached answers invalidated", deleted);
+
+    let mut resp = Response::from_json(&json!({
+        "success": true,
+        "deleted": deleted,
+        "cursor": cursor,
+    }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::ToolCall;
+
+    fn config(cache: Option<CacheConfig>) -> Config {
+        let mut config: Config = serde_json::from_str(
+            r#"{
+                "prompt": {"model": "gpt-3.5-turbo", "messages": [{"role": "system", "content": "Hi"}]},
+                "questions": ["Who is Tom?", "What does Tom do?", "Where is Tom?"],
+                "welcome": "Hi!",
+                "announcement": null
+            }"#,
+        )
+        .unwrap();
+        config.cache = cache;
+        config
+    }
+
+    #[test]
+    fn normalized_questions() {
+        assert_eq!(normalize("  Who   is\nTom? "), "who is tom");
+        assert_eq!(normalize("汤姆是谁？"), "汤姆是谁");
+        assert_eq!(normalize("?!"), "");
+    }
+
+    #[test]
+    fn keys() {
+        let cached = config(Some(CacheConfig::default()));
+        let key = cache_key(&cached, "abc", None, "Who is Tom?", 0).unwrap();
+        assert!(key.starts_with("answer_cache_v0_abc_"));
+        assert_eq!(
+            cache_key(&cached, "abc", None, "who is tom", 0),
+            Some(key.clone())
+        );
+        assert_ne!(
+            cache_key(&cached, "def", None, "Who is Tom?", 0),
+            Some(key.clone())
+        );
+        assert_eq!(cache_key(&cached, "abc", None, "Who is Tom?", 1), None);
+        assert!(cache_key(&cached, "abc", None, "Who is Jerry?", 0).is_some());
+        assert_ne!(
+            cache_key(&cached, "abc", Some("b"), "Who is Tom?", 0),
+            Some(key.clone())
+        );
+
+        // re-saving the same config drops its cached answers
+        let mut saved = cached.clone();
+        saved.version = 1;
+        assert_ne!(cache_key(&saved, "abc", None, "Who is Tom?", 0), Some(key));
+
+        let hints_only = config(Some(CacheConfig {
+            hints_only: true,
+            ..CacheConfig::default()
+        }));
+        assert!(cache_key(&hints_only, "abc", None, "who is tom", 0).is_some());
+        assert_eq!(
+            cache_key(&hints_only, "abc", None, "Who is Jerry?", 0),
+            None
+        );
+
+        assert_eq!(
+            cache_key(&config(None), "abc", None, "Who is Tom?", 0),
+            None
+        );
+    }
+
+    #[test]
+    fn cacheable() {
+        let mut entry = LogKvEntry::new(
+            "What time is it?".to_string(),
+            "It is 9:41.".to_string(),
+            "203.0.113.7".to_string(),
+            "SEA".to_string(),
+            1_700_000_000,
+            "conversation".to_string(),
+            0,
+        );
+        assert!(!is_cacheable(&entry));
+        entry.finish_reason = Some(FinishReason::Stop);
+        assert!(is_cacheable(&entry));
+        entry.tool_calls = vec![ToolCall {
+            id: "call_a".to_string(),
+            name: "current_time".to_string(),
+            arguments: "{}".to_string(),
+        }];
+        assert!(!is_cacheable(&entry));
+        entry.tool_calls.clear();
+        entry.cached = true;
+        assert!(!is_cacheable(&entry));
+        entry.cached = false;
+        entry.finish_reason = Some(FinishReason::Length);
+        assert!(!is_cacheable(&entry));
+    }
+
+    #[test]
+    fn replay() {
+        let answer = "Tom is a **developer** in Seattle.\n\n汤姆是一名软件工程师。";
+        let chunks = replay_chunks(answer);
+        assert_eq!(chunks.concat(), answer);
+        assert_eq!(&chunks[..3], &["Tom ", "is ", "a "]);
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk.trim_end().chars().count() <= 4));
+    }
+}
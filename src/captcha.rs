@@ -0,0 +1,366 @@
+//! Verification of the captcha solved by the visitor before their first question.
+
+use futures_util::future::{self, FutureExt, LocalBoxFuture};
+use serde::{Deserialize, Serialize};
+use worker::{
+    js_sys::encode_uri_component, wasm_bindgen::JsValue, Fetch, Method, Request, RequestInit,
+};
+
+use crate::error::{Error, Result};
+
+/// Which captcha service the frontend uses, as stored in the KV `config`. The `CAPTCHA_PROVIDER`
+/// env var takes precedence, e.g. to use the mock in local development.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CaptchaProvider {
+    #[default]
+    #[serde(rename = "turnstile")]
+    Turnstile,
+    #[serde(rename = "hcaptcha")]
+    HCaptcha,
+    /// Google reCAPTCHA v3
+    #[serde(rename = "recaptcha")]
+    ReCaptcha,
+    /// accepts every token but `fail`, without asking anyone
+    #[serde(rename = "mock")]
+    Mock,
+}
+
+impl CaptchaProvider {
+    /// Parse the value of the `CAPTCHA_PROVIDER` env var.
+    pub fn from_name(name: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(name.trim().to_string())).ok()
+    }
+
+    /// Name of the env var holding the secret key of this provider.
+    pub fn secret_var(&self) -> Option<&'static str> {
+        match self {
+            CaptchaProvider::Turnstile => Some("TURNSTILE_SECRET_KEY"),
+            CaptchaProvider::HCaptcha => Some("HCAPTCHA_SECRET_KEY"),
+            CaptchaProvider::ReCaptcha => Some("RECAPTCHA_SECRET_KEY"),
+            CaptchaProvider::Mock => None,
+        }
+    }
+
+    pub fn build(&self, secret: String, config: &CaptchaConfig) -> Box<dyn CaptchaVerifier> {
+        match self {
+            CaptchaProvider::Turnstile => Box::new(Turnstile { secret }),
+            CaptchaProvider::HCaptcha => Box::new(HCaptcha { secret }),
+            CaptchaProvider::ReCaptcha => Box::new(ReCaptcha {
+                secret,
+                min_score: config.min_score,
+            }),
+            CaptchaProvider::Mock => Box::new(MockVerifier {
+                hostname: config.hostnames.first().cloned(),
+                action: config.action.clone(),
+            }),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct CaptchaConfig {
+    pub provider: CaptchaProvider,
+    /// hostnames the captcha may be solved on, any if empty
+    pub hostnames: Vec<String>,
+    /// action the widget is rendered with, not checked if omitted
+    pub action: Option<String>,
+    /// lowest reCAPTCHA v3 score accepted, from 0.0 (a bot) to 1.0 (a human)
+    pub min_score: f64,
+}
+
+impl Default for CaptchaConfig {
+    fn default() -> Self {
+        Self {
+            provider: CaptchaProvider::default(),
+            hostnames: Vec::new(),
+            action: None,
+            min_score: 0.5,
+        }
+    }
+}
+
+/// Answer of a siteverify endpoint. Turnstile, hCaptcha and reCAPTCHA share this shape, except
+/// that hCaptcha has no `action` and only reCAPTCHA has a `score`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct SiteverifyResponse {
+    pub success: bool,
+    #[serde(rename = "error-codes", default)]
+    pub error_codes: Vec<String>,
+    pub hostname: Option<String>,
+    pub action: Option<String>,
+    pub score: Option<f64>,
+}
+
+/// A captcha service.
+pub trait CaptchaVerifier {
+    /// Ask the service whether `token` was solved.
+    fn siteverify<'a>(
+        &'a self,
+        token: &'a str,
+        remote_ip: &'a str,
+    ) -> LocalBoxFuture<'a, Result<SiteverifyResponse>>;
+
+    /// Whether the score of a successful response is good enough. Only reCAPTCHA v3 scores
+    /// its responses.
+    fn accepts_score(&self, _score: Option<f64>) -> bool {
+        true
+    }
+}
+
+/// Verify `token`, and that it was solved on one of our hostnames with the expected action.
+pub async fn verify(
+    verifier: &dyn CaptchaVerifier,
+    config: &CaptchaConfig,
+    token: &str,
+    remote_ip: &str,
+) -> Result<()> {
+    let resp = verifier.siteverify(token, remote_ip).await?;
+    check(verifier, config, &resp)
+        .map_err(|reason| Error::InvalidRequest(format!("captcha verification failed: {}", reason)))
+}
+
+fn check(
+    verifier: &dyn CaptchaVerifier,
+    config: &CaptchaConfig,
+    resp: &SiteverifyResponse,
+) -> std::result::Result<(), String> {
+    if !resp.success {
+        return Err(format!("{:?}", resp.error_codes));
+    }
+    if !config.hostnames.is_empty()
+        && !resp
+            .hostname
+            .as_ref()
+            .is_some_and(|hostname| config.hostnames.contains(hostname))
+    {
+        return Err(format!("unexpected hostname {:?}", resp.hostname));
+    }
+    if let Some(action) = &config.action {
+        if resp.action.as_ref() != Some(action) {
+            return Err(format!("unexpected action {:?}", resp.action));
+        }
+    }
+    if !verifier.accepts_score(resp.score) {
+        return Err(format!("score {:?} is too low", resp.score));
+    }
+    Ok(())
+}
+
+fn encode_form_data(s: &str) -> Result<String> {
+    encode_uri_component(s)
+        .as_string()
+        .ok_or(Error::InvalidRequest("invalid form data".to_string()))
+}
+
+/// Post a token to a siteverify endpoint. All three services take the same form.
+async fn post_siteverify(
+    url: &str,
+    secret: &str,
+    token: &str,
+    remote_ip: &str,
+) -> Result<SiteverifyResponse> {
+    // we write form body manually because RequestInit::withbody is not supported with FormData
+    let form_body = format!(
+        "secret={}&response={}&remoteip={}",
+        encode_form_data(secret)?,
+        encode_form_data(token)?,
+        encode_form_data(remote_ip)?
+    );
+
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post);
+    init.with_body(Some(JsValue::from_str(&form_body)));
+    let mut req = Request::new_with_init(url, &init)?;
+    req.headers_mut()?
+        .set("Content-Type", "application/x-www-form-urlencoded")?;
+
+    Ok(Fetch::Request(req)
+        .send()
+        .await?
+        .json::<SiteverifyResponse>()
+        .await?)
+}
+
+/// Cloudflare Turnstile
+pub struct Turnstile {
+    secret: String,
+}
+
+impl CaptchaVerifier for Turnstile {
+    fn siteverify<'a>(
+        &'a self,
+        token: &'a str,
+        remote_ip: &'a str,
+    ) -> LocalBoxFuture<'a, Result<SiteverifyResponse>> {
+        post_siteverify(
+            "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+            &self.secret,
+            token,
+            remote_ip,
+        )
+        .boxed_local()
+    }
+}
+
+pub struct HCaptcha {
+    secret: String,
+}
+
+impl CaptchaVerifier for HCaptcha {
+    fn siteverify<'a>(
+        &'a self,
+        token: &'a str,
+        remote_ip: &'a str,
+    ) -> LocalBoxFuture<'a, Result<SiteverifyResponse>> {
+        post_siteverify(
+            "https://api.hcaptcha.com/siteverify",
+            &self.secret,
+            token,
+            remote_ip,
+        )
+        .boxed_local()
+    }
+}
+
+/// Google reCAPTCHA v3, which scores visitors instead of challenging them.
+pub struct ReCaptcha {
+    secret: String,
+    min_score: f64,
+}
+
+impl CaptchaVerifier for ReCaptcha {
+    fn siteverify<'a>(
+        &'a self,
+        token: &'a str,
+        remote_ip: &'a str,
+    ) -> LocalBoxFuture<'a, Result<SiteverifyResponse>> {
+        post_siteverify(
+            "https://www.google.com/recaptcha/api/siteverify",
+            &self.secret,
+            token,
+            remote_ip,
+        )
+        .boxed_local()
+    }
+
+    fn accepts_score(&self, score: Option<f64>) -> bool {
+        score.is_some_and(|score| score >= self.min_score)
+    }
+}
+
+/// Answers like a real service would for the configured hostname and action.
+pub struct MockVerifier {
+    hostname: Option<String>,
+    action: Option<String>,
+}
+
+impl MockVerifier {
+    fn response(&self, token: &str) -> SiteverifyResponse {
+        if token == "fail" {
+            return SiteverifyResponse {
+                error_codes: vec!["invalid-input-response".to_string()],
+                ..SiteverifyResponse::default()
+            };
+        }
+        SiteverifyResponse {
+            success: true,
+            error_codes: Vec::new(),
+            hostname: self.hostname.clone(),
+            action: self.action.clone(),
+            score: Some(1.0),
+        }
+    }
+}
+
+impl CaptchaVerifier for MockVerifier {
+    fn siteverify<'a>(
+        &'a self,
+        token: &'a str,
+        _remote_ip: &'a str,
+    ) -> LocalBoxFuture<'a, Result<SiteverifyResponse>> {
+        future::ready(Ok(self.response(token))).boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CaptchaConfig {
+        CaptchaConfig {
+            hostnames: vec!["example.com".to_string()],
+            action: Some("chat".to_string()),
+            ..CaptchaConfig::default()
+        }
+    }
+
+    #[test]
+    fn check_response() {
+        let config = config();
+        let mock = MockVerifier {
+            hostname: Some("example.com".to_string()),
+            action: Some("chat".to_string()),
+        };
+        assert_eq!(check(&mock, &config, &mock.response("token")), Ok(()));
+        assert_eq!(
+            check(&mock, &config, &mock.response("fail")),
+            Err(r#"["invalid-input-response"]"#.to_string())
+        );
+
+        let resp = SiteverifyResponse {
+            hostname: Some("evil.com".to_string()),
+            ..mock.response("token")
+        };
+        assert!(check(&mock, &config, &resp)
+            .unwrap_err()
+            .contains("hostname"));
+        let resp = SiteverifyResponse {
+            action: Some("login".to_string()),
+            ..mock.response("token")
+        };
+        assert!(check(&mock, &config, &resp).unwrap_err().contains("action"));
+        // nothing is expected of hostname or action unless configured
+        assert_eq!(check(&mock, &CaptchaConfig::default(), &resp), Ok(()));
+    }
+
+    #[test]
+    fn recaptcha_score() {
+        let recaptcha = ReCaptcha {
+            secret: String::new(),
+            min_score: 0.5,
+        };
+        let config = CaptchaConfig::default();
+        let resp: SiteverifyResponse = serde_json::from_str(
+            r#"{"success": true, "score": 0.3, "action": "chat", "hostname": "example.com",
+                "challenge_ts": "2023-05-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+        assert!(check(&recaptcha, &config, &resp)
+            .unwrap_err()
+            .contains("score"));
+        let resp = SiteverifyResponse {
+            score: Some(0.9),
+            ..resp
+        };
+        assert_eq!(check(&recaptcha, &config, &resp), Ok(()));
+        let resp = SiteverifyResponse {
+            score: None,
+            ..resp
+        };
+        assert!(check(&recaptcha, &config, &resp).is_err());
+    }
+
+    #[test]
+    fn provider_from_name() {
+        assert_eq!(
+            CaptchaProvider::from_name("hcaptcha"),
+            Some(CaptchaProvider::HCaptcha)
+        );
+        assert_eq!(
+            CaptchaProvider::from_name("mock"),
+            Some(CaptchaProvider::Mock)
+        );
+        assert_eq!(CaptchaProvider::from_name("recaptcha-v2"), None);
+    }
+}
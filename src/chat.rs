@@ -0,0 +1,638 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futures_channel::mpsc::UnboundedSender;
+use futures_util::StreamExt;
+use serde::Serialize;
+use worker::{
+    console_log, kv::KvStore, Date, Delay, Request, RouteContext, WebSocket, WebsocketEvent,
+};
+
+use crate::apikey::{self, ApiKey, Scope};
+use crate::cache::{self, CachedAnswer};
+use crate::captcha::{self, CaptchaProvider, CaptchaVerifier};
+use crate::constants::{KV_BINDING, KV_LOG_BINDING};
+use crate::error::{Error, Result};
+use crate::experiment::{self, Assignment};
+use crate::input::Locale;
+use crate::knowledge::{self, KnowledgeConfig, ProviderEmbedder};
+use crate::prompt::{self, Config, Message, RequestToOpenAI, Role, UserRequest};
+use crate::ratelimit::RateLimiter;
+use crate::session::{self, SessionClaims};
+use crate::share;
+use crate::stream_parser::{FinishReason, StreamItem};
+use crate::tools::{self, ToolRegistry, ToolResult};
+use crate::upstream::{self, Upstream};
+use crate::usage::{self, TokenUsage};
+use crate::{id, read_config, utils, EndMessage, LogKvEntry};
+
+/// pause between the deltas of an answer replayed from the cache
+const REPLAY_DELAY_MS: u64 = 20;
+
+/// Where the events of a chat go. Every event is a `StreamItem` or an `EndMessage`.
+pub trait ChatSink {
+    fn send<T: Serialize>(&self, event: &T) -> Result<()>;
+
+    /// Whether anyone watches the events as they come, so that replayed answers are paced.
+    fn is_live(&self) -> bool {
+        true
+    }
+}
+
+impl ChatSink for WebSocket {
+    fn send<T: Serialize>(&self, event: &T) -> Result<()> {
+        Ok(WebSocket::send(self, event)?)
+    }
+}
+
+/// Writes events as the `data:` lines of a `text/event-stream` body.
+pub struct SseSink(pub UnboundedSender<String>);
+
+impl ChatSink for SseSink {
+    fn send<T: Serialize>(&self, event: &T) -> Result<()> {
+        let data = serde_json::to_string(event)?;
+        self.0
+            .unbounded_send(format!("data: {}\n\n", data))
+            .map_err(|_| Error::InternalError("client disconnected".to_string()))
+    }
+}
+
+/// Drops every event, for callers that only need the returned `Answer`.
+pub struct NullSink;
+
+impl ChatSink for NullSink {
+    fn send<T: Serialize>(&self, _event: &T) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_live(&self) -> bool {
+        false
+    }
+}
+
+/// Everything about the visitor and the config that a chat needs, read once per request.
+pub struct ChatContext {
+    pub config: Config,
+    /// recorded in the log, to tell answers from different configs apart
+    pub config_hash: String,
+    pub config_version: u32,
+    pub upstreams: Vec<Upstream>,
+    pub captcha: Box<dyn CaptchaVerifier>,
+    /// signs session tokens; none are issued or accepted without it
+    pub session_secret: Option<String>,
+    pub remote_ip: String,
+    pub location: String,
+    /// visitor's UTC offset at the time of the request
+    pub timezone: chrono::FixedOffset,
+    /// prompt variables that don't change during a visit
+    pub variables: HashMap<String, String>,
+    /// language of error messages, unless a question asks for another one
+    pub locale: Locale,
+    /// key presented instead of a captcha
+    pub api_key: Option<ApiKey>,
+    /// who the rate limiter counts questions against: the IP, or the API key
+    pub client: String,
+    pub rate_limiter: RateLimiter,
+    pub kv: KvStore,
+    pub log_kv: KvStore,
+}
+
+impl ChatContext {
+    /// Also refuses the request up front if the visitor is over the rate limit, so that the
+    /// client gets a plain 429 rather than an error in the stream.
+    pub async fn from_request(req: &Request, ctx: &RouteContext<()>) -> Result<Self> {
+        let remote_ip = req
+            .headers()
+            .get("CF-Connecting-IP")?
+            .ok_or_else(|| Error::InvalidRequest("missing CF-Connecting-IP".to_string()))?;
+        let cf = req.cf();
+        let location = format!(
+            "{} - {} - {} - {:?}",
+            cf.colo(),
+            cf.country().unwrap_or_else(|| "unknown".to_string()),
+            cf.city().unwrap_or_else(|| "unknown".to_string()),
+            cf.coordinates().unwrap_or_else(|| (0., 0.)),
+        );
+
+        let config = read_config(ctx).await?;
+        let captcha_provider = match ctx.var("CAPTCHA_PROVIDER") {
+            Ok(name) => CaptchaProvider::from_name(&name.to_string()).ok_or_else(|| {
+                Error::InternalError(format!("unknown CAPTCHA_PROVIDER {}", name.to_string()))
+            })?,
+            Err(_) => config.captcha.provider,
+        };
+        let captcha_secret = match captcha_provider.secret_var() {
+            Some(var) => ctx.var(var)?.to_string(),
+            None => String::new(),
+        };
+        let captcha = captcha_provider.build(captcha_secret, &config.captcha);
+        let session_secret = ctx
+            .var("SESSION_SECRET")
+            .ok()
+            .map(|secret| secret.to_string());
+        let variables = config.template_variables(
+            &cf.country().unwrap_or_default(),
+            &cf.city().unwrap_or_default(),
+        );
+        let mut upstreams = Vec::new();
+        for target in config.targets() {
+            let api_key = match target.provider.api_key_var() {
+                Some(var) => Some(ctx.var(var)?.to_string()),
+                None => None,
+            };
+            upstreams.push(Upstream { target, api_key });
+        }
+        let locale = Locale::from_accept_language(
+            &req.headers().get("Accept-Language")?.unwrap_or_default(),
+        );
+
+        let kv = ctx.kv(KV_BINDING)?;
+        let log_kv = ctx.kv(KV_LOG_BINDING)?;
+
+        let api_key = apikey::authenticate(req, &kv, Scope::Chat).await?;
+        let (client, rate_limit) = match &api_key {
+            Some(api_key) => (
+                api_key.client(),
+                api_key.rate_limit_config(&config.rate_limit),
+            ),
+            None => (remote_ip.clone(), config.rate_limit.clone()),
+        };
+        let rate_limiter = RateLimiter::new(kv.clone(), rate_limit);
+        rate_limiter.check(&client).await?;
+
+        Ok(Self {
+            config_hash: config.hash(),
+            config_version: config.version,
+            config,
+            upstreams,
+            captcha,
+            session_secret,
+            remote_ip,
+            location,
+            timezone: prompt::utc_offset(cf.timezone()),
+            variables,
+            locale,
+            api_key,
+            client,
+            rate_limiter,
+            kv,
+            log_kv,
+        })
+    }
+}
+
+/// State carried from one question of a conversation to the next.
+pub struct Conversation {
+    pub id: String,
+    history: Vec<Message>,
+    /// completion tokens generated so far
+    tokens_used: u32,
+    turn: u32,
+    /// session the visitor proved themselves with, whose questions are counted
+    session: Option<SessionClaims>,
+    /// prompt variant assigned at the first question, if an experiment is running
+    variant: Option<Assignment>,
+}
+
+impl Default for Conversation {
+    fn default() -> Self {
+        Self {
+            id: id::make_id(),
+            history: Vec::new(),
+            tokens_used: 0,
+            turn: 0,
+            session: None,
+            variant: None,
+        }
+    }
+}
+
+/// What became of one question.
+pub struct Answer {
+    /// already sent to the sink
+    pub end: EndMessage,
+    pub answer: String,
+    pub finish_reason: Option<FinishReason>,
+    pub usage: TokenUsage,
+}
+
+/// Chunks of the knowledge base relevant to `question`, as the value of [KNOWLEDGE].
+async fn retrieve_knowledge(
+    chat: &ChatContext,
+    knowledge_config: &KnowledgeConfig,
+    question: &str,
+) -> Result<String> {
+    let index = knowledge::load_index(&chat.kv).await?;
+    // embeddings come from the primary provider
+    let api_key = chat.upstreams.first().and_then(|u| u.api_key.clone());
+    let embedder = ProviderEmbedder::new(
+        &chat.config.provider,
+        api_key,
+        &knowledge_config.embedding_model,
+    )?;
+    let chunks = knowledge::retrieve(&embedder, &index, knowledge_config, question).await?;
+    Ok(knowledge::format_chunks(&chunks))
+}
+
+/// Answer one question of `conversation`, sending the events to `sink`. Returns `None` if the
+/// question was refused with an announcement.
+pub async fn answer(
+    chat: &ChatContext,
+    conversation: &mut Conversation,
+    user_request: UserRequest,
+    sink: &impl ChatSink,
+) -> Result<Option<Answer>> {
+    let config = &chat.config;
+    let budget = &config.budget;
+    let turn = conversation.turn;
+
+    // visitors prove they are human once per conversation, unless the client has an API key:
+    // with a session token from an earlier chat, or with a captcha, which earns them a token
+    let mut session_token = None;
+    if turn == 0 && chat.api_key.is_none() {
+        let now = id::get_utc_timestamp_sec();
+        let session = match (&user_request.session_token, &chat.session_secret) {
+            (Some(token), Some(secret)) => {
+                Some(session::decode(token, secret, &chat.remote_ip, now))
+            }
+            _ => None,
+        };
+        match session {
+            Some(Ok(claims)) => conversation.session = Some(claims),
+            // without a captcha to fall back on, tell the client to show one
+            Some(Err(e)) if user_request.captcha_token.is_none() => return Err(e),
+            _ => {
+                let captcha_token = user_request.captcha_token.ok_or(Error::InvalidRequest(
+                    "captcha token is missing".to_string(),
+                ))?;
+                captcha::verify(
+                    chat.captcha.as_ref(),
+                    &chat.config.captcha,
+                    &captcha_token,
+                    &chat.remote_ip,
+                )
+                .await?;
+                if let Some(secret) = &chat.session_secret {
+                    let (token, claims) =
+                        session::issue(&config.session, secret, &chat.remote_ip, now);
+                    conversation.session = Some(claims);
+                    session_token = Some(token);
+                }
+            }
+        }
+    }
+
+    // check the question before it counts against the rate limit
+    let locale = user_request
+        .locale
+        .as_deref()
+        .map_or(chat.locale, Locale::from_accept_language);
+    let question = config.limits.sanitize(&user_request.question);
+    config
+        .limits
+        .check(&question, locale)
+        .map_err(Error::InvalidInput)?;
+
+    chat.rate_limiter.acquire(&chat.client).await?;
+    if let Some(claims) = &conversation.session {
+        session::use_question(&chat.kv, claims, id::get_utc_timestamp_sec()).await?;
+    }
+
+    // the same visitor gets the same variant: per session, or per client without one
+    if turn == 0 {
+        if let Some(experiment) = &config.experiment {
+            let subject = match &conversation.session {
+                Some(claims) => &claims.id,
+                None => &chat.client,
+            };
+            conversation.variant = experiment.assign(subject).map(|variant| Assignment {
+                seed: experiment.seed.clone(),
+                name: variant.name.clone(),
+            });
+        }
+    }
+    let variant = conversation.variant.as_ref().and_then(|assignment| {
+        config
+            .experiment
+            .as_ref()
+            .and_then(|experiment| experiment.variant(&assignment.name))
+    });
+    let active_prompt = match variant {
+        Some(variant) => variant.apply(&config.prompt),
+        None => config.prompt.clone(),
+    };
+    let upstreams = upstream::with_primary_model(&chat.upstreams, &active_prompt.model);
+
+    let cache_key = cache::cache_key(
+        config,
+        &chat.config_hash,
+        variant.map(|variant| variant.name.as_str()),
+        &question,
+        turn,
+    );
+    let cached = match &cache_key {
+        Some(key) => cache::get(&chat.kv, key).await?,
+        None => None,
+    };
+
+    // cached answers cost nothing, so they are served even when the budget is used up
+    if cached.is_none()
+        && budget.has_limits()
+        && budget.is_exhausted(&usage::get_today_usage(&chat.kv).await?)
+    {
+        sink.send(&StreamItem::Announcement(budget.exhausted_message()))?;
+        return Ok(None);
+    }
+
+    let mut variables = chat.variables.clone();
+    prompt::insert_time_variables(&mut variables, chat.timezone);
+    if let (Some(knowledge_config), None) = (&config.knowledge, &cached) {
+        // answer without the knowledge base rather than not at all
+        match retrieve_knowledge(chat, knowledge_config, &question).await {
+            Ok(knowledge) => {
+                variables.insert("KNOWLEDGE".to_string(), knowledge);
+            }
+            Err(e) => console_log!("knowledge retrieval failed: {}", e),
+        }
+    }
+    let mut request_to_openai = RequestToOpenAI::new(
+        active_prompt.clone(),
+        &conversation.history,
+        question.clone(),
+        &variables,
+    )?;
+    // never generate more than what is left of the conversation budget
+    let remaining_tokens = config
+        .conversation
+        .max_tokens
+        .saturating_sub(conversation.tokens_used);
+    request_to_openai.max_tokens = request_to_openai.max_tokens.min(remaining_tokens);
+    sink.send(&StreamItem::Start(request_to_openai.max_tokens))?;
+
+    // the total timeout covers waiting for the first token as well as streaming
+    let deadline = config
+        .timeouts
+        .total_ms
+        .map(|ms| Date::now().as_millis() + ms);
+    let remaining = || deadline.map(|d| d.saturating_sub(Date::now().as_millis()));
+
+    let mut model = active_prompt.model.clone();
+    let mut attempts = Vec::new();
+    let mut chatbot_answer = String::new();
+    let mut reported_usage: Option<TokenUsage> = None;
+    let mut finish_reason: Option<FinishReason> = None;
+
+    let tools = config
+        .tools
+        .as_ref()
+        .map(|tools| ToolRegistry::new(tools, chat.timezone, id::get_utc_timestamp_sec()));
+    if let Some(tools) = &tools {
+        request_to_openai.tools = tools.definitions();
+    }
+    let max_iterations = config
+        .tools
+        .as_ref()
+        .map_or(0, |tools| tools.max_iterations);
+    let mut tool_calls_made = Vec::new();
+
+    if let Some(hit) = &cached {
+        sink.send(&StreamItem::Model(hit.model.clone()))?;
+        for chunk in cache::replay_chunks(&hit.answer) {
+            sink.send(&StreamItem::Delta(chunk))?;
+            if sink.is_live() {
+                Delay::from(Duration::from_millis(REPLAY_DELAY_MS)).await;
+            }
+        }
+        sink.send(&StreamItem::Finish(FinishReason::Stop))?;
+        model = hit.model.clone();
+        chatbot_answer = hit.answer.clone();
+        finish_reason = Some(FinishReason::Stop);
+        // nothing was generated
+        reported_usage = Some(TokenUsage::default());
+    } else {
+        // each round either answers, or calls tools whose results go into the next round
+        for round in 0..=max_iterations {
+            if round > 0 && round == max_iterations {
+                request_to_openai.tool_choice = Some("none".to_string());
+            }
+            let mut tool_calls = Vec::new();
+            // text the model wrote along with its tool calls
+            let mut round_text = String::new();
+            finish_reason = None;
+
+            let completion = upstream::start_completion(
+                &upstreams,
+                &request_to_openai,
+                config.timeouts.first_token_ms,
+            );
+            match utils::timeout(completion, remaining()).await {
+                Some(Ok(completion)) => {
+                    if round == 0 || completion.model != model {
+                        sink.send(&StreamItem::Model(completion.model.clone()))?;
+                    }
+                    model = completion.model;
+                    attempts.extend(completion.attempts);
+                    let mut json_stream = completion.stream;
+
+                    loop {
+                        let wait = utils::earliest(config.timeouts.between_tokens_ms, remaining());
+                        let msg = match utils::timeout(json_stream.next(), wait).await {
+                            Some(Some(msg)) => msg,
+                            Some(None) => break,
+                            None => {
+                                finish_reason = Some(FinishReason::Timeout);
+                                break;
+                            }
+                        };
+                        match msg {
+                            Err(_) => {
+                                finish_reason = Some(FinishReason::Unavailable);
+                                sink.send(&StreamItem::Finish(FinishReason::Unavailable))?;
+                            }
+                            Ok(StreamItem::RoleMsg) => continue,
+                            Ok(StreamItem::Usage(usage)) => {
+                                reported_usage = Some(reported_usage.unwrap_or_default() + usage);
+                            }
+                            // the answer goes on once the tools have run
+                            Ok(StreamItem::Finish(FinishReason::ToolCalls)) if tools.is_some() => {
+                                finish_reason = Some(FinishReason::ToolCalls);
+                            }
+                            Ok(msg) => {
+                                match &msg {
+                                    StreamItem::Delta(delta) => {
+                                        chatbot_answer.push_str(delta);
+                                        round_text.push_str(delta);
+                                    }
+                                    StreamItem::Finish(reason) => {
+                                        finish_reason = Some(reason.clone())
+                                    }
+                                    StreamItem::ToolCalls(calls) => tool_calls = calls.clone(),
+                                    _ => {}
+                                }
+                                sink.send(&msg)?
+                            }
+                        }
+                    }
+                }
+                // every upstream was too slow to start
+                Some(Err(Error::UpstreamTimeout)) | None => {
+                    finish_reason = Some(FinishReason::Timeout);
+                }
+                Some(Err(err)) => return Err(err),
+            }
+
+            let tools = match &tools {
+                Some(tools) if finish_reason == Some(FinishReason::ToolCalls) => tools,
+                _ => break,
+            };
+            if round == max_iterations {
+                // the model kept calling tools even when told not to
+                console_log!("no answer after {} rounds of tool calls", max_iterations);
+                sink.send(&StreamItem::Finish(FinishReason::ToolCalls))?;
+                break;
+            }
+            let mut outputs = Vec::new();
+            for call in &tool_calls {
+                let (output, success) = tools.run(call).await;
+                sink.send(&StreamItem::ToolResult(ToolResult {
+                    id: call.id.clone(),
+                    name: call.name.clone(),
+                    success,
+                }))?;
+                outputs.push(output);
+            }
+            request_to_openai
+                .tool_messages
+                .extend(tools::round_messages(&round_text, &tool_calls, &outputs));
+            tool_calls_made.extend(tool_calls);
+        }
+    }
+
+    let timed_out = finish_reason == Some(FinishReason::Timeout);
+    if timed_out {
+        console_log!("upstream timed out after {} chars", chatbot_answer.len());
+        sink.send(&StreamItem::Finish(FinishReason::Timeout))?;
+    }
+
+    // create an ID for this chat
+    let id = id::make_id();
+    let timestamp = id::get_utc_timestamp_sec();
+    let (deletion_secret, deletion_secret_hash) = share::make_deletion_secret();
+
+    let mut entry = LogKvEntry::new(
+        question.clone(),
+        chatbot_answer.clone(),
+        chat.remote_ip.clone(),
+        chat.location.clone(),
+        timestamp,
+        conversation.id.clone(),
+        turn,
+    );
+    // fall back to our own estimate if the upstream doesn't report usage
+    let token_usage = reported_usage.unwrap_or_else(|| TokenUsage {
+        prompt_tokens: usage::estimate_prompt_tokens(&request_to_openai.messages),
+        completion_tokens: usage::estimate_tokens(&chatbot_answer),
+        estimated: true,
+    });
+    conversation.tokens_used += token_usage.completion_tokens;
+    let cost_usd = budget.cost_usd(&model, &token_usage);
+
+    entry.attempts = attempts;
+    entry.usage = Some(token_usage);
+    entry.finish_reason = finish_reason.clone();
+    entry.deletion_secret_hash = Some(deletion_secret_hash);
+    entry.config_hash = Some(chat.config_hash.clone());
+    entry.config_version = Some(chat.config_version);
+    entry.tool_calls = tool_calls_made;
+    entry.cached = cached.is_some();
+    entry.variant = conversation.variant.clone();
+    entry.session_id = conversation
+        .session
+        .as_ref()
+        .map(|claims| claims.id.clone());
+
+    if let Some(key) = &cache_key {
+        if cache::is_cacheable(&entry) {
+            let answer = CachedAnswer {
+                answer: chatbot_answer.clone(),
+                model: model.clone(),
+                created_at: timestamp,
+            };
+            let ttl_hours = config.cache.as_ref().map_or(0, |cache| cache.ttl_hours);
+            cache::put(&chat.kv, key, &answer, ttl_hours).await?;
+        }
+    }
+
+    // log the chat to KV, including a partial answer
+    chat.log_kv.put(&id, &entry)?.execute().await?;
+    usage::record_usage(&chat.kv, &token_usage, cost_usd, timed_out).await?;
+    if let Some(variant) = &conversation.variant {
+        experiment::record_chat(
+            &chat.kv,
+            variant,
+            &chatbot_answer,
+            token_usage.completion_tokens,
+        )
+        .await?;
+    }
+
+    conversation.history.push(Message {
+        role: Role::User,
+        content: question,
+    });
+    conversation.history.push(Message {
+        role: Role::Assistant,
+        content: chatbot_answer.clone(),
+    });
+    conversation.turn += 1;
+
+    let remaining_turns = if conversation.tokens_used >= config.conversation.max_tokens {
+        0
+    } else {
+        config
+            .conversation
+            .max_turns
+            .saturating_sub(conversation.turn)
+    };
+
+    let end = EndMessage {
+        id,
+        conversation_id: conversation.id.clone(),
+        remaining_turns,
+        session_token,
+        deletion_secret,
+    };
+    sink.send(&end)?;
+    Ok(Some(Answer {
+        end,
+        answer: chatbot_answer,
+        finish_reason,
+        usage: token_usage,
+    }))
+}
+
+/// Wait for the next question from the client. Returns `None` if the client closed the socket.
+async fn next_user_request(events: &mut worker::EventStream<'_>) -> Result<Option<UserRequest>> {
+    match events.next().await {
+        Some(event) => match event? {
+            WebsocketEvent::Message(msg) => Ok(Some(msg.json::<UserRequest>()?)),
+            WebsocketEvent::Close(_) => Ok(None),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Answer questions from the socket until the conversation runs out of turns or tokens.
+pub async fn serve_chat_in_ws(chat: &ChatContext, server: &WebSocket) -> Result<()> {
+    let mut events = server.events()?;
+    let mut conversation = Conversation::default();
+
+    while let Some(user_request) = next_user_request(&mut events).await? {
+        match answer(chat, &mut conversation, user_request, server).await? {
+            Some(answer) if answer.end.remaining_turns > 0 => {}
+            _ => break,
+        }
+    }
+
+    Ok(())
+}
@@ -1,2 +1,4 @@
 pub const KV_BINDING: &str = "salieri";
 pub const KV_LOG_BINDING: &str = "log";
+/// number of suggested questions returned by `/api/salieri/hint`
+pub const NUM_QUESTIONS_SAMPLED: usize = 3;
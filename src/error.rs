@@ -2,14 +2,23 @@ use serde_json::json;
 use thiserror::Error;
 use worker::Response;
 
+use crate::input::InputError;
+use crate::prompt::FieldError;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("invalid request: {0}")]
     InvalidRequest(String),
+    #[error("invalid input: {}", .0.message)]
+    InvalidInput(InputError),
+    #[error("invalid config")]
+    InvalidConfig(Vec<FieldError>),
     #[error("forbidden")]
     Forbidden,
     #[error("not found: {0}")]
     NotFound(String),
+    #[error("too many requests, retry after {0} seconds")]
+    TooManyRequests(u64),
     #[error("internal error: worker")]
     WorkerError(#[from] worker::Error),
     #[error("internal error: kv")]
@@ -18,38 +27,58 @@ pub enum Error {
     SerdeJsonError(#[from] serde_json::Error),
     #[error("OpenAI error: {0}")]
     OpenAIError(u16, String),
+    #[error("upstream timed out")]
+    UpstreamTimeout,
+    #[error("unavailable: {0}")]
+    Unavailable(String),
     #[error("internal error")]
     InternalError(String),
 }
 
 impl Error {
-    fn status_code(&self) -> u16 {
+    pub(crate) fn status_code(&self) -> u16 {
         match self {
             Error::InvalidRequest(_) => 400,
+            Error::InvalidInput(_) => 400,
+            Error::InvalidConfig(_) => 400,
             Error::Forbidden => 403,
             Error::NotFound(_) => 404,
+            Error::TooManyRequests(_) => 429,
             Error::WorkerError(_) => 500,
             Error::KvError(_) => 500,
             Error::SerdeJsonError(_) => 500,
             Error::OpenAIError(_, _) => 500,
+            Error::UpstreamTimeout => 504,
+            Error::Unavailable(_) => 503,
             Error::InternalError(_) => 500,
         }
     }
 
     fn json(&self) -> serde_json::Value {
-        json!({
+        let mut json = json!({
             "error": self.to_string(),
             "status_code": self.status_code(),
-        })
+        });
+        match self {
+            Error::InvalidInput(input) => json["input"] = json!(input),
+            Error::InvalidConfig(errors) => json["errors"] = json!(errors),
+            _ => {}
+        }
+        json
     }
 }
 
 impl From<Error> for Response {
     fn from(err: Error) -> Self {
         let error_code = err.status_code();
-        let resp = Response::from_json(&err.json())
+        let mut resp = Response::from_json(&err.json())
             .unwrap()
             .with_status(error_code);
+        if let Error::TooManyRequests(retry_after) = err {
+            resp.headers_mut()
+                .set("Retry-After", &retry_after.to_string())
+                .unwrap();
+        }
         resp
     }
 }
@@ -0,0 +1,319 @@
+//! A/B tests of prompts: each visitor is assigned one of several weighted variants of the
+//! prompt, and answers and feedback are counted per variant.
+//!
+//! Assignment hashes the seed with the visitor's session id, or with the client (IP or API key)
+//! without a session, so a visitor keeps their variant until the seed changes.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use worker::{kv::KvStore, Request, Response, RouteContext};
+
+use crate::admin::{query_param, verify_read_access};
+use crate::attach_origin_to_header;
+use crate::constants::KV_BINDING;
+use crate::error::{Error, Result};
+use crate::feedback::{FeedbackCounts, Rating};
+use crate::prompt::{Message, Prompt};
+use crate::read_config;
+
+const STATS_PREFIX: &str = "variant_stats_";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExperimentConfig {
+    /// changing it reassigns every visitor, and starts the stats over
+    pub seed: String,
+    pub variants: Vec<PromptVariant>,
+}
+
+/// A variant of `Config.prompt`. Fields that are omitted are taken from the prompt.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PromptVariant {
+    /// lowercase letters, digits, `-` and `_`; recorded in the log
+    pub name: String,
+    /// share of visitors relative to the other variants; 0 pauses the variant
+    pub weight: u32,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub messages: Option<Vec<Message>>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+}
+
+impl PromptVariant {
+    pub fn apply(&self, prompt: &Prompt) -> Prompt {
+        Prompt {
+            model: self.model.clone().unwrap_or_else(|| prompt.model.clone()),
+            messages: self
+                .messages
+                .clone()
+                .unwrap_or_else(|| prompt.messages.clone()),
+            max_tokens: self.max_tokens.or(prompt.max_tokens),
+        }
+    }
+}
+
+/// The variant a chat was answered with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    pub seed: String,
+    pub name: String,
+}
+
+impl ExperimentConfig {
+    /// The variant of the visitor identified by `subject`, `None` if every weight is 0.
+    pub fn assign(&self, subject: &str) -> Option<&PromptVariant> {
+        let total: u64 = self.variants.iter().map(|v| v.weight as u64).sum();
+        if total == 0 {
+            return None;
+        }
+        let hash = Sha256::digest(format!("{}:{}", self.seed, subject).as_bytes());
+        let mut point = hash[..8]
+            .iter()
+            .fold(0u64, |acc, byte| acc << 8 | *byte as u64)
+            % total;
+        for variant in &self.variants {
+            if point < variant.weight as u64 {
+                return Some(variant);
+            }
+            point -= variant.weight as u64;
+        }
+        None
+    }
+
+    pub fn variant(&self, name: &str) -> Option<&PromptVariant> {
+        self.variants.iter().find(|variant| variant.name == name)
+    }
+}
+
+pub fn is_variant_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// What was answered with a variant.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VariantStats {
+    pub chats: u64,
+    /// characters of all answers, for the average length
+    pub answer_chars: u64,
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub feedback: FeedbackCounts,
+}
+
+impl VariantStats {
+    fn to_json(self) -> serde_json::Value {
+        let average = |total: u64| (self.chats > 0).then(|| total as f64 / self.chats as f64);
+        json!({
+            "chats": self.chats,
+            "avg_answer_chars": average(self.answer_chars),
+            "avg_completion_tokens": average(self.completion_tokens),
+            "up": self.feedback.up,
+            "down": self.feedback.down,
+            "satisfaction": self.feedback.satisfaction(),
+        })
+    }
+}
+
+fn stats_prefix(seed: &str) -> String {
+    // seeds are free-form, so they are hashed to keep keys and prefixes apart
+    let hash = Sha256::digest(seed.as_bytes());
+    format!("{}{}_", STATS_PREFIX, hex::encode(&hash[..6]))
+}
+
+fn stats_key(assignment: &Assignment) -> String {
+    format!("{}{}", stats_prefix(&assignment.seed), assignment.name)
+}
+
+/// Read-modify-write, so concurrent chats may be lost, as with usage.
+async fn update_stats(
+    kv: &KvStore,
+    assignment: &Assignment,
+    update: impl FnOnce(&mut VariantStats),
+) -> Result<()> {
+    let key = stats_key(assignment);
+    let mut stats = kv
+        .get(&key)
+        .json::<VariantStats>()
+        .await?
+        .unwrap_or_default();
+    update(&mut stats);
+    kv.put(&key, stats)?.execute().await?;
+    Ok(())
+}
+
+pub async fn record_chat(
+    kv: &KvStore,
+    assignment: &Assignment,
+    answer: &str,
+    completion_tokens: u32,
+) -> Result<()> {
+    update_stats(kv, assignment, |stats| {
+        stats.chats += 1;
+        stats.answer_chars += answer.chars().count() as u64;
+        stats.completion_tokens += completion_tokens as u64;
+    })
+    .await
+}
+
+pub async fn record_feedback(
+    kv: &KvStore,
+    assignment: &Assignment,
+    rating: Rating,
+    previous: Option<Rating>,
+) -> Result<()> {
+    update_stats(kv, assignment, |stats| {
+        stats.feedback.record(rating, previous)
+    })
+    .await
+}
+
+/// Stats per variant of the live experiment, or of the one with `?seed=`.
+pub async fn handle_experiment_stats(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    verify_read_access(&req, &ctx).await?;
+
+    let experiment = read_config(&ctx).await?.experiment;
+    let seed = match (query_param(&req, "seed"), &experiment) {
+        (Ok(seed), _) => seed,
+        (Err(_), Some(experiment)) => experiment.seed.clone(),
+        (Err(_), None) => {
+            return Err(Error::InvalidRequest(
+                "the config has no experiment; pass `seed` for an earlier one".to_string(),
+            ))
+        }
+    };
+
+    let kv = ctx.kv(KV_BINDING)?;
+    let prefix = stats_prefix(&seed);
+    let mut variants = Vec::new();
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(prefix.clone());
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        for key in page.keys {
+            if let Some(stats) = kv.get(&key.name).json::<VariantStats>().await? {
+                let name = key.name.trim_start_matches(&prefix);
+                let mut json = stats.to_json();
+                json["name"] = json!(name);
+                // `null` for variants no longer in the config
+                json["weight"] = json!(experiment
+                    .as_ref()
+                    .filter(|experiment| experiment.seed == seed)
+                    .and_then(|experiment| experiment.variant(name))
+                    .map(|variant| variant.weight));
+                variants.push(json);
+            }
+        }
+        if page.list_complete {
+            break;
+        }
+        cursor = page.cursor;
+    }
+
+    let mut resp = Response::from_json(&json!({ "seed": seed, "variants": variants }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prompt::Role;
+
+    fn variant(name: &str, weight: u32) -> PromptVariant {
+        PromptVariant {
+            name: name.to_string(),
+            weight,
+            model: None,
+            messages: None,
+            max_tokens: None,
+        }
+    }
+
+    fn experiment(seed: &str, variants: Vec<PromptVariant>) -> ExperimentConfig {
+        ExperimentConfig {
+            seed: seed.to_string(),
+            variants,
+        }
+    }
+
+    fn share_of(experiment: &ExperimentConfig, name: &str) -> f64 {
+        let hits = (0..10_000)
+            .filter(|i| {
+                experiment
+                    .assign(&format!("203.0.{}.{}", i / 256, i % 256))
+                    .unwrap()
+                    .name
+                    == name
+            })
+            .count();
+        hits as f64 / 10_000.
+    }
+
+    #[test]
+    fn sticky_weighted_assignment() {
+        let ab = experiment("spring", vec![variant("a", 1), variant("b", 3)]);
+        let first = ab.assign("203.0.113.7").unwrap().name.clone();
+        for _ in 0..10 {
+            assert_eq!(ab.assign("203.0.113.7").unwrap().name, first);
+        }
+        assert!((share_of(&ab, "a") - 0.25).abs() < 0.02);
+
+        let paused = experiment("spring", vec![variant("a", 0), variant("b", 1)]);
+        assert_eq!(share_of(&paused, "b"), 1.0);
+        assert_eq!(
+            experiment("spring", vec![variant("a", 0)]).assign("x"),
+            None
+        );
+
+        // another seed reshuffles visitors
+        let reseeded = experiment("summer", vec![variant("a", 1), variant("b", 3)]);
+        assert!((0..100)
+            .map(|i| i.to_string())
+            .any(|subject| ab.assign(&subject) != reseeded.assign(&subject)));
+    }
+
+    #[test]
+    fn apply_variant() {
+        let prompt = Prompt {
+            model: "gpt-3.5-turbo".to_string(),
+            messages: vec![Message {
+                role: Role::System,
+                content: "Be brief.".to_string(),
+            }],
+            max_tokens: Some(200),
+        };
+        let mut gpt4 = variant("gpt4", 1);
+        gpt4.model = Some("gpt-4".to_string());
+        let applied = gpt4.apply(&prompt);
+        assert_eq!(applied.model, "gpt-4");
+        assert_eq!(applied.messages, prompt.messages);
+        assert_eq!(applied.max_tokens, Some(200));
+    }
+
+    #[test]
+    fn stats() {
+        let mut stats = VariantStats::default();
+        assert_eq!(stats.to_json()["avg_answer_chars"], json!(null));
+        stats.chats = 4;
+        stats.answer_chars = 1000;
+        stats.feedback.record(Rating::Up, None);
+        let json = stats.to_json();
+        assert_eq!(json["avg_answer_chars"], json!(250.0));
+        assert_eq!(json["satisfaction"], json!(1.0));
+
+        let a = Assignment {
+            seed: "spring".to_string(),
+            name: "a".to_string(),
+        };
+        assert!(stats_key(&a).starts_with(&stats_prefix("spring")));
+        assert!(!stats_key(&a).starts_with(&stats_prefix("spring2")));
+    }
+}
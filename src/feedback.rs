@@ -0,0 +1,341 @@
+//! Thumbs up or down from visitors on answers, kept in the log entry of the chat, with
+//! satisfaction counters per day and per config version.
+
+use chrono::Datelike;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::cmp::Reverse;
+use worker::{kv::KvStore, Request, Response, RouteContext};
+
+use crate::admin::{query_param, verify_read_access};
+use crate::constants::{KV_BINDING, KV_LOG_BINDING};
+use crate::error::{Error, Result};
+use crate::ratelimit::{RateLimitConfig, RateLimiter};
+use crate::{attach_origin_to_header, experiment, id, share};
+
+const MAX_COMMENT_CHARS: usize = 1000;
+const MAX_TAGS: usize = 5;
+const MAX_TAG_CHARS: usize = 32;
+const FEEDBACK_PER_IP_PER_MINUTE: u32 = 10;
+const FEEDBACK_PER_IP_PER_DAY: u32 = 200;
+/// days of stats returned when `?days=` is omitted
+const DEFAULT_STATS_DAYS: u32 = 30;
+const MAX_STATS_DAYS: u32 = 90;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rating {
+    #[serde(rename = "up")]
+    Up,
+    #[serde(rename = "down")]
+    Down,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Feedback {
+    pub rating: Rating,
+    #[serde(default)]
+    pub comment: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// seconds since epoch; a visitor who rates again replaces their feedback
+    pub timestamp: i64,
+}
+
+#[derive(Deserialize)]
+struct FeedbackRequest {
+    /// id of the chat, from its end message
+    id: String,
+    /// from the same end message, so that only the visitor who asked can rate the answer
+    deletion_secret: String,
+    rating: Rating,
+    comment: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+impl FeedbackRequest {
+    /// Trim the comment and tags, and check them against the limits.
+    fn into_feedback(self, timestamp: i64) -> Result<Feedback> {
+        let comment = self
+            .comment
+            .map(|comment| comment.trim().to_string())
+            .filter(|comment| !comment.is_empty());
+        if comment
+            .as_ref()
+            .is_some_and(|comment| comment.chars().count() > MAX_COMMENT_CHARS)
+        {
+            return Err(Error::InvalidRequest(format!(
+                "comments are limited to {} characters",
+                MAX_COMMENT_CHARS
+            )));
+        }
+
+        let mut tags: Vec<String> = Vec::new();
+        for tag in self.tags {
+            let tag = tag.trim().to_lowercase();
+            if tag.is_empty() || tags.contains(&tag) {
+                continue;
+            }
+            if tag.chars().count() > MAX_TAG_CHARS {
+                return Err(Error::InvalidRequest(format!(
+                    "tags are limited to {} characters",
+                    MAX_TAG_CHARS
+                )));
+            }
+            tags.push(tag);
+        }
+        if tags.len() > MAX_TAGS {
+            return Err(Error::InvalidRequest(format!(
+                "at most {} tags are allowed",
+                MAX_TAGS
+            )));
+        }
+
+        Ok(Feedback {
+            rating: self.rating,
+            comment,
+            tags,
+            timestamp,
+        })
+    }
+}
+
+/// Ratings of a day or a config version.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FeedbackCounts {
+    pub up: u64,
+    pub down: u64,
+}
+
+impl FeedbackCounts {
+    /// Count `rating`, which replaces `previous` if the visitor rated before.
+    pub(crate) fn record(&mut self, rating: Rating, previous: Option<Rating>) {
+        if previous == Some(rating) {
+            return;
+        }
+        match previous {
+            Some(Rating::Up) => self.up = self.up.saturating_sub(1),
+            Some(Rating::Down) => self.down = self.down.saturating_sub(1),
+            None => {}
+        }
+        match rating {
+            Rating::Up => self.up += 1,
+            Rating::Down => self.down += 1,
+        }
+    }
+
+    /// Share of thumbs up, `None` without any ratings.
+    pub fn satisfaction(&self) -> Option<f64> {
+        let total = self.up + self.down;
+        (total > 0).then(|| self.up as f64 / total as f64)
+    }
+
+    fn to_json(self) -> serde_json::Value {
+        json!({
+            "up": self.up,
+            "down": self.down,
+            "satisfaction": self.satisfaction(),
+        })
+    }
+}
+
+fn daily_stats_key(date: &str) -> String {
+    format!("feedback_stats_{}", date)
+}
+
+const VERSION_STATS_PREFIX: &str = "feedback_stats_version_";
+
+fn version_stats_key(config_version: u32) -> String {
+    format!("{}{}", VERSION_STATS_PREFIX, config_version)
+}
+
+/// Read-modify-write of one counter, so concurrent ratings may be lost, as with usage.
+async fn update_counts(
+    kv: &KvStore,
+    key: &str,
+    rating: Rating,
+    previous: Option<Rating>,
+) -> Result<()> {
+    let mut counts = kv
+        .get(key)
+        .json::<FeedbackCounts>()
+        .await?
+        .unwrap_or_default();
+    counts.record(rating, previous);
+    kv.put(key, counts)?.execute().await?;
+    Ok(())
+}
+
+pub async fn handle_feedback(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let remote_ip = req
+        .headers()
+        .get("CF-Connecting-IP")?
+        .ok_or_else(|| Error::InvalidRequest("missing CF-Connecting-IP".to_string()))?;
+    let feedback_req: FeedbackRequest = serde_json::from_str(&req.text().await?)
+        .map_err(|e| Error::InvalidRequest(format!("malformed feedback: {}", e)))?;
+    let id = feedback_req.id.clone();
+    let deletion_secret = feedback_req.deletion_secret.clone();
+    let feedback = feedback_req.into_feedback(id::get_utc_timestamp_sec())?;
+
+    let kv = ctx.kv(KV_BINDING)?;
+    let limiter = RateLimiter::new(
+        kv.clone(),
+        RateLimitConfig {
+            per_ip_per_minute: Some(FEEDBACK_PER_IP_PER_MINUTE),
+            per_ip_per_day: Some(FEEDBACK_PER_IP_PER_DAY),
+            ..RateLimitConfig::default()
+        },
+    );
+    limiter.acquire(&format!("feedback_{}", remote_ip)).await?;
+
+    let log_kv = ctx.kv(KV_LOG_BINDING)?;
+    let mut entry = share::owned_entry(&log_kv, &id, &deletion_secret).await?;
+    let previous = entry.feedback.as_ref().map(|feedback| feedback.rating);
+    let rating = feedback.rating;
+    entry.feedback = Some(feedback);
+    log_kv.put(&id, &entry)?.execute().await?;
+
+    // log ids start with the date of the chat
+    let date = id.get(..10).unwrap_or_default();
+    update_counts(&kv, &daily_stats_key(date), rating, previous).await?;
+    // chats from before configs were versioned only count per day
+    if let Some(config_version) = entry.config_version {
+        update_counts(&kv, &version_stats_key(config_version), rating, previous).await?;
+    }
+    if let Some(variant) = &entry.variant {
+        experiment::record_feedback(&kv, variant, rating, previous).await?;
+    }
+
+    let mut resp = Response::from_json(&json!({ "success": true }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+/// Satisfaction per day over the last `?days=` days, newest first, and per config version,
+/// newest first.
+pub async fn handle_feedback_stats(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    verify_read_access(&req, &ctx).await?;
+
+    let days = match query_param(&req, "days") {
+        Ok(days) => days
+            .parse::<u32>()
+            .map_err(|_| Error::InvalidRequest("`days` must be a number".to_string()))?
+            .clamp(1, MAX_STATS_DAYS),
+        Err(_) => DEFAULT_STATS_DAYS,
+    };
+
+    let kv = ctx.kv(KV_BINDING)?;
+    let mut daily = Vec::new();
+    let mut date = chrono::DateTime::from_timestamp(id::get_utc_timestamp_sec(), 0)
+        .ok_or_else(|| Error::InternalError("clock out of range".to_string()))?
+        .date_naive();
+    for _ in 0..days {
+        let day = format!("{:04}-{:02}-{:02}", date.year(), date.month(), date.day());
+        let counts = kv
+            .get(&daily_stats_key(&day))
+            .json::<FeedbackCounts>()
+            .await?
+            .unwrap_or_default();
+        let mut stats = counts.to_json();
+        stats["date"] = json!(day);
+        daily.push(stats);
+        date = match date.pred_opt() {
+            Some(date) => date,
+            None => break,
+        };
+    }
+
+    let mut versions = Vec::new();
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(VERSION_STATS_PREFIX.to_string());
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        for key in page.keys {
+            let version = match key.name[VERSION_STATS_PREFIX.len()..].parse::<u32>() {
+                Ok(version) => version,
+                Err(_) => continue,
+            };
+            if let Some(counts) = kv.get(&key.name).json::<FeedbackCounts>().await? {
+                versions.push((version, counts));
+            }
+        }
+        if page.list_complete {
+            break;
+        }
+        cursor = page.cursor;
+    }
+
+    // keys list in string order, which puts 10 before 9
+    versions.sort_by_key(|(version, _)| Reverse(*version));
+    let configs: Vec<_> = versions
+        .into_iter()
+        .map(|(version, counts)| {
+            let mut stats = counts.to_json();
+            stats["config_version"] = json!(version);
+            stats
+        })
+        .collect();
+
+    let mut resp = Response::from_json(&json!({ "daily": daily, "configs": configs }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(comment: Option<&str>, tags: &[&str]) -> FeedbackRequest {
+        FeedbackRequest {
+            id: "2023-05-01-abc".to_string(),
+            deletion_secret: "secret".to_string(),
+            rating: Rating::Down,
+            comment: comment.map(str::to_string),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn feedback_request() {
+        let feedback = request(Some("  wrong year  "), &["Outdated", " outdated", ""])
+            .into_feedback(1_000)
+            .unwrap();
+        assert_eq!(feedback.comment.as_deref(), Some("wrong year"));
+        assert_eq!(feedback.tags, vec!["outdated"]);
+
+        assert_eq!(
+            request(Some(" "), &[])
+                .into_feedback(1_000)
+                .unwrap()
+                .comment,
+            None
+        );
+        assert!(request(Some(&"a".repeat(1001)), &[])
+            .into_feedback(1_000)
+            .is_err());
+        assert!(request(None, &["a", "b", "c", "d", "e", "f"])
+            .into_feedback(1_000)
+            .is_err());
+        assert!(request(None, &[&"t".repeat(33)])
+            .into_feedback(1_000)
+            .is_err());
+    }
+
+    #[test]
+    fn counts() {
+        let mut counts = FeedbackCounts::default();
+        assert_eq!(counts.satisfaction(), None);
+        counts.record(Rating::Up, None);
+        counts.record(Rating::Up, None);
+        counts.record(Rating::Down, None);
+        // a visitor changing their mind moves their rating
+        counts.record(Rating::Up, Some(Rating::Down));
+        counts.record(Rating::Up, Some(Rating::Up));
+        assert_eq!(counts, FeedbackCounts { up: 3, down: 0 });
+        counts.record(Rating::Down, Some(Rating::Up));
+        assert_eq!(counts.satisfaction(), Some(2.0 / 3.0));
+    }
+}
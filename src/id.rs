@@ -4,7 +4,7 @@ pub fn get_utc_timestamp_sec() -> i64 {
     now_timestamp as i64
 }
 
-fn get_utc_date() -> String {
+pub fn get_utc_date() -> String {
     use chrono::prelude::*;
     let now_timestamp = get_utc_timestamp_sec();
     let naive_datetime =
@@ -0,0 +1,235 @@
+use serde::{Deserialize, Serialize};
+
+/// Zero-width characters removed by `strip_zero_width`. The zero-width joiner (U+200D) is kept,
+/// since it holds emoji sequences together.
+const ZERO_WIDTH: [char; 4] = ['\u{200B}', '\u{200C}', '\u{2060}', '\u{FEFF}'];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct InputLimits {
+    /// maximum length of a question in characters, not bytes
+    pub max_chars: usize,
+    pub max_lines: Option<usize>,
+    /// remove control characters other than newlines and tabs
+    pub strip_control: bool,
+    pub strip_zero_width: bool,
+    /// collapse runs of spaces and blank lines, and trim every line
+    pub normalize_whitespace: bool,
+}
+
+impl Default for InputLimits {
+    fn default() -> Self {
+        Self {
+            max_chars: 300,
+            max_lines: None,
+            strip_control: true,
+            strip_zero_width: true,
+            normalize_whitespace: true,
+        }
+    }
+}
+
+/// Language of messages shown to the visitor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Locale {
+    En,
+    Zh,
+}
+
+impl Locale {
+    /// Pick the first supported language in an `Accept-Language` header or a single tag such as
+    /// `zh-CN`. Falls back to English.
+    pub fn from_accept_language(header: &str) -> Self {
+        header
+            .split(',')
+            .filter_map(|tag| {
+                let tag = tag.split(';').next()?.trim().to_ascii_lowercase();
+                if tag.starts_with("zh") {
+                    Some(Locale::Zh)
+                } else if tag.starts_with("en") {
+                    Some(Locale::En)
+                } else {
+                    None
+                }
+            })
+            .next()
+            .unwrap_or(Locale::En)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputErrorCode {
+    #[serde(rename = "empty")]
+    Empty,
+    #[serde(rename = "too_long")]
+    TooLong,
+    #[serde(rename = "too_many_lines")]
+    TooManyLines,
+}
+
+/// Why a question was refused, with enough detail for the frontend to show a counter.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct InputError {
+    pub code: InputErrorCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual: Option<usize>,
+    /// localized, ready to show
+    pub message: String,
+}
+
+impl InputError {
+    fn new(
+        code: InputErrorCode,
+        limit: Option<usize>,
+        actual: Option<usize>,
+        locale: Locale,
+    ) -> Self {
+        let (limit_value, actual_value) = (limit.unwrap_or(0), actual.unwrap_or(0));
+        let message = match (code, locale) {
+            (InputErrorCode::Empty, Locale::En) => "Please enter a question.".to_string(),
+            (InputErrorCode::Empty, Locale::Zh) => "请输入问题。".to_string(),
+            (InputErrorCode::TooLong, Locale::En) => format!(
+                "Your question is too long ({}/{} characters).",
+                actual_value, limit_value
+            ),
+            (InputErrorCode::TooLong, Locale::Zh) => {
+                format!("问题太长了（{}/{} 字）。", actual_value, limit_value)
+            }
+            (InputErrorCode::TooManyLines, Locale::En) => format!(
+                "Your question has too many lines ({}/{}).",
+                actual_value, limit_value
+            ),
+            (InputErrorCode::TooManyLines, Locale::Zh) => {
+                format!("问题的行数太多了（{}/{} 行）。", actual_value, limit_value)
+            }
+        };
+        Self {
+            code,
+            limit,
+            actual,
+            message,
+        }
+    }
+}
+
+impl InputLimits {
+    /// Clean up `question` according to the policy. Newlines are normalized to `\n`.
+    pub fn sanitize(&self, question: &str) -> String {
+        let question = question.replace("\r\n", "\n");
+        let question: String = question
+            .chars()
+            .filter(|c| !(self.strip_control && c.is_control() && *c != '\n' && *c != '\t'))
+            .filter(|c| !(self.strip_zero_width && ZERO_WIDTH.contains(c)))
+            .collect();
+        if !self.normalize_whitespace {
+            return question;
+        }
+
+        let mut lines: Vec<String> = Vec::new();
+        for line in question.lines() {
+            let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+            // keep at most one blank line in a row
+            if line.is_empty() && lines.last().is_none_or(|last| last.is_empty()) {
+                continue;
+            }
+            lines.push(line);
+        }
+        while lines.last().is_some_and(|last| last.is_empty()) {
+            lines.pop();
+        }
+        lines.join("\n")
+    }
+
+    /// Check a sanitized question against the limits.
+    pub fn check(&self, question: &str, locale: Locale) -> Result<(), InputError> {
+        if question.trim().is_empty() {
+            return Err(InputError::new(InputErrorCode::Empty, None, None, locale));
+        }
+        let chars = question.chars().count();
+        if chars > self.max_chars {
+            return Err(InputError::new(
+                InputErrorCode::TooLong,
+                Some(self.max_chars),
+                Some(chars),
+                locale,
+            ));
+        }
+        if let Some(max_lines) = self.max_lines {
+            let lines = question.lines().count();
+            if lines > max_lines {
+                return Err(InputError::new(
+                    InputErrorCode::TooManyLines,
+                    Some(max_lines),
+                    Some(lines),
+                    locale,
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize() {
+        let limits = InputLimits::default();
+        assert_eq!(
+            limits.sanitize("  Who\u{200B} is\t\tTom?\r\n\r\n\r\n\u{0007}And   you? \n\n"),
+            "Who is Tom?\n\nAnd you?"
+        );
+        // emoji sequences survive
+        assert_eq!(limits.sanitize("👨\u{200D}💻"), "👨\u{200D}💻");
+
+        let limits = InputLimits {
+            strip_control: false,
+            strip_zero_width: false,
+            normalize_whitespace: false,
+            ..InputLimits::default()
+        };
+        assert_eq!(
+            limits.sanitize(" a\u{200B}\u{0007} "),
+            " a\u{200B}\u{0007} "
+        );
+    }
+
+    #[test]
+    fn check() {
+        let limits = InputLimits {
+            max_chars: 5,
+            max_lines: Some(2),
+            ..InputLimits::default()
+        };
+        // characters, not bytes
+        assert_eq!(limits.check("你好你好你", Locale::Zh), Ok(()));
+
+        let err = limits.check("你好你好你好", Locale::Zh).unwrap_err();
+        assert_eq!(err.code, InputErrorCode::TooLong);
+        assert_eq!((err.limit, err.actual), (Some(5), Some(6)));
+        assert_eq!(err.message, "问题太长了（6/5 字）。");
+
+        let err = limits.check("a\nb\nc", Locale::En).unwrap_err();
+        assert_eq!(err.code, InputErrorCode::TooManyLines);
+        assert_eq!(err.message, "Your question has too many lines (3/2).");
+
+        let err = limits.check(" ", Locale::En).unwrap_err();
+        assert_eq!(
+            serde_json::to_value(&err).unwrap(),
+            serde_json::json!({"code": "empty", "message": "Please enter a question."})
+        );
+    }
+
+    #[test]
+    fn locale() {
+        assert_eq!(
+            Locale::from_accept_language("fr-FR,zh-CN;q=0.9,en;q=0.8"),
+            Locale::Zh
+        );
+        assert_eq!(Locale::from_accept_language("en-US,zh;q=0.5"), Locale::En);
+        assert_eq!(Locale::from_accept_language(""), Locale::En);
+    }
+}
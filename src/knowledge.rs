@@ -0,0 +1,620 @@
+//! Knowledge base: documents uploaded by the admin, split into chunks and embedded, of which
+//! the ones closest to a question are put into the prompt as [KNOWLEDGE].
+//!
+//! Every chunk with its vector lives in a single KV entry, so that a chat reads one key.
+
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use worker::{
+    console_log, kv::KvStore, wasm_bindgen::JsValue, Fetch, Headers, Method, Request, RequestInit,
+    Response, RouteContext,
+};
+
+use crate::admin::{query_param, verify_identity, verify_read_access};
+use crate::constants::KV_BINDING;
+use crate::error::{Error, Result};
+use crate::provider::{Provider, ProviderConfig};
+use crate::{attach_origin_to_header, id, read_config};
+
+const DOC_PREFIX: &str = "knowledge_doc_";
+const INDEX_KEY: &str = "knowledge_index";
+/// texts per embedding request
+const EMBEDDING_BATCH: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct KnowledgeConfig {
+    /// embedding model of the config's provider; for Azure, the name of its deployment
+    pub embedding_model: String,
+    /// chunks put into the prompt at most
+    pub top_k: usize,
+    /// chunks less similar to the question than this are left out, from -1.0 to 1.0
+    pub min_score: f32,
+    /// longest chunk, in characters
+    pub chunk_chars: usize,
+    /// characters repeated between the pieces of a paragraph too long for one chunk
+    pub chunk_overlap: usize,
+}
+
+impl Default for KnowledgeConfig {
+    fn default() -> Self {
+        Self {
+            embedding_model: "text-embedding-3-small".to_string(),
+            top_k: 3,
+            min_score: 0.3,
+            chunk_chars: 800,
+            chunk_overlap: 100,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DocFormat {
+    #[default]
+    #[serde(rename = "markdown")]
+    Markdown,
+    #[serde(rename = "text")]
+    Text,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Document {
+    /// lowercase letters, digits, `-` and `_`
+    pub id: String,
+    pub title: String,
+    #[serde(default)]
+    pub format: DocFormat,
+    pub content: String,
+    /// seconds since epoch
+    #[serde(default)]
+    pub updated_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub doc_id: String,
+    pub title: String,
+    pub text: String,
+    pub vector: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct KnowledgeIndex {
+    /// model the vectors come from; vectors of different models can't be compared
+    pub model: String,
+    pub chunks: Vec<Chunk>,
+}
+
+/// Turns texts into vectors.
+pub trait Embedder {
+    fn embed<'a>(&'a self, texts: &'a [String]) -> LocalBoxFuture<'a, Result<Vec<Vec<f32>>>>;
+}
+
+/// Embeds through the embeddings API of a provider.
+pub struct ProviderEmbedder {
+    provider: Box<dyn Provider>,
+    endpoint: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+impl ProviderEmbedder {
+    pub fn new(provider: &ProviderConfig, api_key: Option<String>, model: &str) -> Result<Self> {
+        let built = provider.build();
+        let endpoint = built.embeddings_endpoint(model).ok_or_else(|| {
+            Error::InvalidRequest(format!("{} has no embeddings API", provider.name()))
+        })?;
+        Ok(Self {
+            provider: built,
+            endpoint,
+            api_key,
+            model: model.to_string(),
+        })
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let mut headers = Headers::new();
+        for (name, value) in self.provider.headers(self.api_key.as_deref()) {
+            headers.append(name, &value)?;
+        }
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post);
+        init.with_headers(headers);
+        let body = json!({ "model": self.model, "input": texts });
+        init.with_body(Some(JsValue::from_str(&serde_json::to_string(&body)?)));
+
+        let request = Request::new_with_init(&self.endpoint, &init)?;
+        let mut response = Fetch::Request(request).send().await?;
+        let status = response.status_code();
+        if status != 200 {
+            return Err(Error::OpenAIError(status, response.text().await?));
+        }
+        decode_embeddings(&response.text().await?, texts.len())
+    }
+}
+
+impl Embedder for ProviderEmbedder {
+    fn embed<'a>(&'a self, texts: &'a [String]) -> LocalBoxFuture<'a, Result<Vec<Vec<f32>>>> {
+        async move {
+            let mut vectors = Vec::with_capacity(texts.len());
+            for batch in texts.chunks(EMBEDDING_BATCH) {
+                vectors.extend(self.embed_batch(batch).await?);
+            }
+            Ok(vectors)
+        }
+        .boxed_local()
+    }
+}
+
+/// Vectors of an OpenAI-style embeddings response, in the order of the inputs.
+fn decode_embeddings(body: &str, expected: usize) -> Result<Vec<Vec<f32>>> {
+    #[derive(Deserialize)]
+    struct Embedding {
+        index: usize,
+        embedding: Vec<f32>,
+    }
+    #[derive(Deserialize)]
+    struct EmbeddingsResponse {
+        data: Vec<Embedding>,
+    }
+
+    let mut data = serde_json::from_str::<EmbeddingsResponse>(body)?.data;
+    data.sort_by_key(|embedding| embedding.index);
+    if data.len() != expected {
+        return Err(Error::InternalError(format!(
+            "asked for {} embeddings, got {}",
+            expected,
+            data.len()
+        )));
+    }
+    Ok(data.into_iter().map(|e| e.embedding).collect())
+}
+
+/// Split a document into chunks of at most `max_chars` characters along paragraphs. Markdown
+/// headings start a new chunk. Paragraphs too long for one chunk are cut into pieces that
+/// overlap by `overlap` characters.
+pub fn chunk(content: &str, format: DocFormat, max_chars: usize, overlap: usize) -> Vec<String> {
+    let content = content.replace("\r\n", "\n");
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    for paragraph in content.split("\n\n").map(str::trim) {
+        if paragraph.is_empty() {
+            continue;
+        }
+        let paragraph_chars = paragraph.chars().count();
+        let starts_section = format == DocFormat::Markdown && paragraph.starts_with('#');
+        if !current.is_empty()
+            && (starts_section || current.chars().count() + 2 + paragraph_chars > max_chars)
+        {
+            chunks.push(std::mem::take(&mut current));
+        }
+
+        if paragraph_chars > max_chars {
+            let chars: Vec<char> = paragraph.chars().collect();
+            let step = max_chars.saturating_sub(overlap).max(1);
+            let mut start = 0;
+            loop {
+                let end = (start + max_chars).min(chars.len());
+                chunks.push(chars[start..end].iter().collect());
+                if end == chars.len() {
+                    break;
+                }
+                start += step;
+            }
+            continue;
+        }
+
+        if !current.is_empty() {
+            current.push_str("\n\n");
+        }
+        current.push_str(paragraph);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0. {
+        0.
+    } else {
+        dot / norms
+    }
+}
+
+/// The `config.top_k` chunks closest to `query`, most similar first.
+fn top_k<'a>(index: &'a KnowledgeIndex, query: &[f32], config: &KnowledgeConfig) -> Vec<&'a Chunk> {
+    let mut scored: Vec<(f32, &Chunk)> = index
+        .chunks
+        .iter()
+        .map(|chunk| (cosine_similarity(query, &chunk.vector), chunk))
+        .filter(|(score, _)| *score >= config.min_score)
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored
+        .into_iter()
+        .take(config.top_k)
+        .map(|(_, chunk)| chunk)
+        .collect()
+}
+
+/// Chunks of `index` relevant to `question`, most relevant first. None if the index was built
+/// with another model.
+pub async fn retrieve<'a>(
+    embedder: &dyn Embedder,
+    index: &'a KnowledgeIndex,
+    config: &KnowledgeConfig,
+    question: &str,
+) -> Result<Vec<&'a Chunk>> {
+    if index.chunks.is_empty() {
+        return Ok(Vec::new());
+    }
+    // vectors of another model mean nothing to this one, until the admin reindexes
+    if index.model != config.embedding_model {
+        return Ok(Vec::new());
+    }
+    let query = embedder
+        .embed(&[question.to_string()])
+        .await?
+        .pop()
+        .unwrap_or_default();
+    Ok(top_k(index, &query, config))
+}
+
+/// Value of [KNOWLEDGE]: each chunk under the title of its document.
+pub fn format_chunks(chunks: &[&Chunk]) -> String {
+    chunks
+        .iter()
+        .map(|chunk| format!("## {}\n\n{}", chunk.title, chunk.text))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Chunk and embed `doc`.
+async fn embed_document(
+    embedder: &dyn Embedder,
+    config: &KnowledgeConfig,
+    doc: &Document,
+) -> Result<Vec<Chunk>> {
+    let texts = chunk(
+        &doc.content,
+        doc.format,
+        config.chunk_chars,
+        config.chunk_overlap,
+    );
+    let vectors = embedder.embed(&texts).await?;
+    Ok(texts
+        .into_iter()
+        .zip(vectors)
+        .map(|(text, vector)| Chunk {
+            doc_id: doc.id.clone(),
+            title: doc.title.clone(),
+            text,
+            vector,
+        })
+        .collect())
+}
+
+pub async fn load_index(kv: &KvStore) -> Result<KnowledgeIndex> {
+    Ok(kv
+        .get(INDEX_KEY)
+        .json::<KnowledgeIndex>()
+        .await?
+        .unwrap_or_default())
+}
+
+async fn save_index(kv: &KvStore, index: &KnowledgeIndex) -> Result<()> {
+    kv.put(INDEX_KEY, index)?.execute().await?;
+    Ok(())
+}
+
+async fn list_documents(kv: &KvStore) -> Result<Vec<Document>> {
+    let mut docs = Vec::new();
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(DOC_PREFIX.to_string());
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        for key in page.keys {
+            if let Some(doc) = kv.get(&key.name).json::<Document>().await? {
+                docs.push(doc);
+            }
+        }
+        if page.list_complete {
+            break;
+        }
+        cursor = page.cursor;
+    }
+    Ok(docs)
+}
+
+/// The knowledge config and an embedder for it, which need the config to have a knowledge base.
+async fn embedder_for(ctx: &RouteContext<()>) -> Result<(KnowledgeConfig, ProviderEmbedder)> {
+    let config = read_config(ctx).await?;
+    let knowledge = config.knowledge.ok_or_else(|| {
+        Error::InvalidRequest("the config has no `knowledge` section".to_string())
+    })?;
+    let api_key = match config.provider.api_key_var() {
+        Some(var) => Some(ctx.var(var)?.to_string()),
+        None => None,
+    };
+    let embedder = ProviderEmbedder::new(&config.provider, api_key, &knowledge.embedding_model)?;
+    Ok((knowledge, embedder))
+}
+
+fn is_doc_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// Documents without their content.
+pub async fn handle_knowledge_list(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    verify_read_access(&req, &ctx).await?;
+
+    let kv = ctx.kv(KV_BINDING)?;
+    let index = load_index(&kv).await?;
+    let docs: Vec<serde_json::Value> = list_documents(&kv)
+        .await?
+        .into_iter()
+        .map(|doc| {
+            json!({
+                "id": doc.id,
+                "title": doc.title,
+                "format": doc.format,
+                "chars": doc.content.chars().count(),
+                "chunks": index.chunks.iter().filter(|c| c.doc_id == doc.id).count(),
+                "updated_at": doc.updated_at,
+            })
+        })
+        .collect();
+
+    let mut resp = Response::from_json(&json!({ "model": index.model, "documents": docs }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+/// Add or replace a document, and embed it.
+pub async fn handle_knowledge_upload(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    verify_identity(&req, &ctx.env).await?;
+
+    let mut doc: Document = serde_json::from_str(&req.text().await?)
+        .map_err(|e| Error::InvalidRequest(format!("malformed document: {}", e)))?;
+    if !is_doc_id(&doc.id) {
+        return Err(Error::InvalidRequest(
+            "document ids may only hold lowercase letters, digits, - and _".to_string(),
+        ));
+    }
+    if doc.content.trim().is_empty() {
+        return Err(Error::InvalidRequest("the document is empty".to_string()));
+    }
+    doc.updated_at = id::get_utc_timestamp_sec();
+
+    let (config, embedder) = embedder_for(&ctx).await?;
+    let chunks = embed_document(&embedder, &config, &doc).await?;
+    let chunk_count = chunks.len();
+
+    let kv = ctx.kv(KV_BINDING)?;
+    let mut index = load_index(&kv).await?;
+    if index.model != config.embedding_model {
+        // vectors of another model are useless next to the new ones
+        index = KnowledgeIndex {
+            model: config.embedding_model.clone(),
+            chunks: Vec::new(),
+        };
+    }
+    index.chunks.retain(|chunk| chunk.doc_id != doc.id);
+    index.chunks.extend(chunks);
+    kv.put(&format!("{}{}", DOC_PREFIX, doc.id), &doc)?
+        .execute()
+        .await?;
+    save_index(&kv, &index).await?;
+    console_log!("knowledge document {} has {} chunks", doc.id, chunk_count);
+
+    let mut resp = Response::from_json(&json!({ "id": doc.id, "chunks": chunk_count }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+pub async fn handle_knowledge_delete(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    verify_identity(&req, &ctx.env).await?;
+
+    let id = query_param(&req, "id")?;
+    let kv = ctx.kv(KV_BINDING)?;
+    let key = format!("{}{}", DOC_PREFIX, id);
+    if kv.get(&key).text().await?.is_none() {
+        return Err(Error::NotFound(format!("No document found with id {}", id)));
+    }
+    let mut index = load_index(&kv).await?;
+    index.chunks.retain(|chunk| chunk.doc_id != id);
+    save_index(&kv, &index).await?;
+    kv.delete(&key).await?;
+
+    let mut resp = Response::from_json(&json!({ "success": true }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+/// Chunk and embed every document again, e.g. after changing the embedding model or the
+/// chunk sizes.
+pub async fn handle_knowledge_reindex(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    verify_identity(&req, &ctx.env).await?;
+
+    let (config, embedder) = embedder_for(&ctx).await?;
+    let kv = ctx.kv(KV_BINDING)?;
+    let docs = list_documents(&kv).await?;
+    let mut index = KnowledgeIndex {
+        model: config.embedding_model.clone(),
+        chunks: Vec::new(),
+    };
+    for doc in &docs {
+        index
+            .chunks
+            .extend(embed_document(&embedder, &config, doc).await?);
+    }
+    save_index(&kv, &index).await?;
+
+    let mut resp = Response::from_json(&json!({
+        "documents": docs.len(),
+        "chunks": index.chunks.len(),
+    }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future;
+
+    const DIMENSIONS: usize = 256;
+
+    /// Bag of words hashed into a few dimensions, so texts sharing words are close.
+    struct FakeEmbedder;
+
+    impl FakeEmbedder {
+        fn vector(text: &str) -> Vec<f32> {
+            let mut vector = vec![0.; DIMENSIONS];
+            for word in text
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|w| !w.is_empty())
+            {
+                // FNV-1a, which is stable across runs unlike the std hasher
+                let hash = word
+                    .to_lowercase()
+                    .bytes()
+                    .fold(0xcbf29ce484222325u64, |h, b| {
+                        (h ^ b as u64).wrapping_mul(0x100000001b3)
+                    });
+                vector[(hash % DIMENSIONS as u64) as usize] += 1.;
+            }
+            vector
+        }
+    }
+
+    impl Embedder for FakeEmbedder {
+        fn embed<'a>(&'a self, texts: &'a [String]) -> LocalBoxFuture<'a, Result<Vec<Vec<f32>>>> {
+            future::ready(Ok(texts.iter().map(|t| Self::vector(t)).collect())).boxed_local()
+        }
+    }
+
+    fn doc(id: &str, title: &str, content: &str) -> Document {
+        Document {
+            id: id.to_string(),
+            title: title.to_string(),
+            format: DocFormat::Markdown,
+            content: content.to_string(),
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn chunking() {
+        let content =
+            "# Work\r\n\r\nTom works on compilers.\n\nHe likes Rust.\n\n# Hobbies\n\nHiking.";
+        assert_eq!(
+            chunk(content, DocFormat::Markdown, 100, 10),
+            vec![
+                "# Work\n\nTom works on compilers.\n\nHe likes Rust.",
+                "# Hobbies\n\nHiking."
+            ]
+        );
+        assert_eq!(
+            chunk(content, DocFormat::Text, 100, 10),
+            vec!["# Work\n\nTom works on compilers.\n\nHe likes Rust.\n\n# Hobbies\n\nHiking."]
+        );
+        // long paragraphs are cut with overlap, by characters
+        assert_eq!(
+            chunk("一二三四五六七八九十", DocFormat::Text, 4, 1),
+            vec!["一二三四", "四五六七", "七八九十"]
+        );
+    }
+
+    #[test]
+    fn retrieval() {
+        let config = KnowledgeConfig {
+            embedding_model: "fake".to_string(),
+            top_k: 2,
+            min_score: 0.1,
+            ..KnowledgeConfig::default()
+        };
+        let docs = [
+            doc("cv", "CV", "Tom studied computer science at Stanford."),
+            doc(
+                "projects",
+                "Projects",
+                "Salieri is a chatbot written in Rust.",
+            ),
+            doc("hobbies", "Hobbies", "Tom plays the piano and goes hiking."),
+        ];
+        let mut index = KnowledgeIndex {
+            model: "fake".to_string(),
+            chunks: Vec::new(),
+        };
+        for doc in &docs {
+            let chunks = embed_document(&FakeEmbedder, &config, doc)
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+            index.chunks.extend(chunks);
+        }
+
+        let found = retrieve(&FakeEmbedder, &index, &config, "Where did Tom study?")
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(found[0].doc_id, "cv");
+        assert!(found.len() <= 2);
+        assert!(format_chunks(&found).starts_with("## CV\n\nTom studied"));
+
+        let found = retrieve(
+            &FakeEmbedder,
+            &index,
+            &config,
+            "Is Salieri written in Rust?",
+        )
+        .now_or_never()
+        .unwrap()
+        .unwrap();
+        assert_eq!(found[0].doc_id, "projects");
+
+        // nothing close enough
+        let found = retrieve(&FakeEmbedder, &index, &config, "xyzzy")
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert!(found.is_empty());
+
+        // vectors of another model aren't compared
+        index.model = "other".to_string();
+        let found = retrieve(&FakeEmbedder, &index, &config, "Where did Tom study?")
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert!(found.is_empty());
+    }
+
+    #[test]
+    fn embeddings_response() {
+        let body = r#"{"object": "list", "data": [
+            {"object": "embedding", "index": 1, "embedding": [0.5, 0.5]},
+            {"object": "embedding", "index": 0, "embedding": [1.0, 0.0]}
+        ], "model": "text-embedding-3-small"}"#;
+        assert_eq!(
+            decode_embeddings(body, 2).unwrap(),
+            vec![vec![1.0, 0.0], vec![0.5, 0.5]]
+        );
+        assert!(decode_embeddings(body, 3).is_err());
+    }
+}
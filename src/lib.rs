@@ -1,26 +1,52 @@
 use crate::error::Result;
 use futures_util::StreamExt;
-use prompt::{Config, Prompt, UserRequest};
+use prompt::{Config, UserRequest};
 use rand::{seq::SliceRandom, SeedableRng};
 use serde_json::json;
 use wasm_bindgen_futures::spawn_local;
 use worker::{
-    console_error, console_log, event, js_sys::encode_uri_component, wasm_bindgen::JsValue,
-    wasm_bindgen_futures, Date, Env, Fetch, Headers, Method, Request, RequestInit, Response,
-    Result as WorkerResult, RouteContext, Router, WebSocket, WebSocketPair, WebsocketEvent,
+    console_error, console_log, event, wasm_bindgen_futures, Date, Env, Headers, Request, Response,
+    Result as WorkerResult, RouteContext, Router, WebSocketPair,
 };
 
 mod admin;
+mod apikey;
+mod cache;
+mod captcha;
+mod chat;
 mod constants;
 mod error;
+mod experiment;
+mod feedback;
 mod id;
+mod input;
+mod knowledge;
+mod logs;
 mod prompt;
+mod provider;
+mod ratelimit;
+mod session;
+mod share;
+mod share_page;
+mod sse;
 mod stream_parser;
+mod template;
+mod tools;
+mod upstream;
+mod usage;
 mod utils;
 
 use constants::*;
 
-use crate::{prompt::RequestToOpenAI, stream_parser::StreamItem};
+use crate::{
+    chat::{ChatContext, ChatSink, Conversation, NullSink, SseSink},
+    experiment::Assignment,
+    feedback::Feedback,
+    provider::Attempt,
+    stream_parser::{FinishReason, StreamItem},
+    tools::ToolCall,
+    usage::TokenUsage,
+};
 
 fn log_request(req: &Request) {
     console_log!(
@@ -80,59 +106,17 @@ fn attach_origin_header_to_resp(req: &Request, resp: &mut Response) -> Result<()
     Ok(attach_origin_to_header(req, &mut headers)?)
 }
 
-#[derive(serde::Deserialize)]
-struct CaptchaResponse {
-    success: bool,
-    #[serde(rename = "error-codes")]
-    error_codes: Vec<String>,
-}
-
-fn encode_form_data(s: &str) -> Result<String> {
-    Ok(encode_uri_component(s)
-        .as_string()
-        .ok_or(error::Error::InvalidRequest(
-            "invalid form data".to_string(),
-        ))?)
-}
-async fn verify_captcha(
-    token: &str,
-    turnstile_secret_key: &str,
-    remote_ip: &str,
-) -> Result<CaptchaResponse> {
-    // let mut form_data = FormData::new();
-    // form_data.append("secret", turnstile_secret_key)?;
-    // form_data.append("token", &token)?;
-    // form_data.append("remoteip", remote_ip)?;
-    // we write form body manually because RequestInit::withbody is not supported with FormDat
-
-    let mut form_body = String::new();
-    form_body.push_str("secret=");
-    form_body.push_str(&encode_form_data(turnstile_secret_key)?);
-    form_body.push_str("&response=");
-    form_body.push_str(&encode_form_data(token)?);
-    form_body.push_str("&remoteip=");
-    form_body.push_str(&encode_form_data(remote_ip)?);
-
-    let mut init = RequestInit::new();
-    init.with_method(Method::Post);
-    init.with_body(Some(JsValue::from_str(&form_body)));
-    let mut req = Request::new_with_init(
-        "https://challenges.cloudflare.com/turnstile/v0/siteverify",
-        &init,
-    )?;
-    req.headers_mut()?
-        .set("Content-Type", "application/x-www-form-urlencoded")?;
-
-    Ok(Fetch::Request(req)
-        .send()
-        .await?
-        .json::<CaptchaResponse>()
-        .await?)
-}
-
 #[derive(serde::Serialize)]
 pub struct EndMessage {
     pub id: String,
+    pub conversation_id: String,
+    /// number of questions the client can still ask before the socket is closed
+    pub remaining_turns: u32,
+    /// issued when a captcha was solved, to send instead of one in later chats
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_token: Option<String>,
+    /// lets the visitor share or delete this chat
+    pub deletion_secret: String,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -142,6 +126,44 @@ pub struct LogKvEntry {
     pub remote_ip: String,
     pub location: String,
     pub timestamp: i64,
+    #[serde(default)]
+    pub conversation_id: Option<String>,
+    #[serde(default)]
+    pub turn: u32,
+    /// upstream targets tried for this answer, the answering one last
+    #[serde(default)]
+    pub attempts: Vec<Attempt>,
+    #[serde(default)]
+    pub usage: Option<TokenUsage>,
+    /// how the answer ended, e.g. `timeout` if the upstream stalled
+    #[serde(default)]
+    pub finish_reason: Option<FinishReason>,
+    /// SHA-256 of the secret that lets the visitor share or delete the chat
+    #[serde(default)]
+    pub deletion_secret_hash: Option<String>,
+    /// slug the chat is shared as, if the visitor shared it
+    #[serde(default)]
+    pub share: Option<String>,
+    /// hash of the config the chat was answered under, see `Config::hash`
+    #[serde(default)]
+    pub config_hash: Option<String>,
+    /// version of that config, see `Config::version`
+    #[serde(default)]
+    pub config_version: Option<u32>,
+    #[serde(default)]
+    pub feedback: Option<Feedback>,
+    /// tools the model called before answering, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// whether the answer was served from the cache
+    #[serde(default)]
+    pub cached: bool,
+    /// prompt variant the chat was answered with, if an experiment was running
+    #[serde(default)]
+    pub variant: Option<Assignment>,
+    /// id of the session the visitor chatted with, to revoke it
+    #[serde(default)]
+    pub session_id: Option<String>,
 }
 
 impl LogKvEntry {
@@ -151,6 +173,8 @@ impl LogKvEntry {
         remote_ip: String,
         location: String,
         timestamp: i64,
+        conversation_id: String,
+        turn: u32,
     ) -> Self {
         Self {
             question,
@@ -158,119 +182,25 @@ impl LogKvEntry {
             remote_ip,
             location,
             timestamp,
+            conversation_id: Some(conversation_id),
+            turn,
+            attempts: Vec::new(),
+            usage: None,
+            finish_reason: None,
+            deletion_secret_hash: None,
+            share: None,
+            config_hash: None,
+            config_version: None,
+            feedback: None,
+            tool_calls: Vec::new(),
+            cached: false,
+            variant: None,
+            session_id: None,
         }
     }
 }
 
-// TODO: enforce length limit, and cloudflare turnstile
-pub async fn serve_chat_in_ws(
-    openai_key: &str,
-    turnstile_secret_key: &str,
-    remote_ip: &str,
-    location: &str,
-    timezone: impl chrono::TimeZone,
-    server: WebSocket,
-    prompt: Prompt,
-    log_kv: &worker::kv::KvStore,
-) -> Result<()> {
-    // wait for the first message from the client
-    let first_msg = server.events()?.next().await.unwrap()?;
-    let user_request = match first_msg {
-        WebsocketEvent::Message(msg) => msg.json::<UserRequest>()?,
-        WebsocketEvent::Close(_) => {
-            // server.close::<String>(None, None)?;
-            return Ok(());
-        }
-    };
-
-    // verify captcha
-    let captcha_token = user_request
-        .captcha_token
-        .ok_or(error::Error::InvalidRequest(
-            "captcha token is missing".to_string(),
-        ))?;
-    let captcha_resp = verify_captcha(&captcha_token, turnstile_secret_key, remote_ip).await?;
-    if !captcha_resp.success {
-        return Err(error::Error::InvalidRequest(format!(
-            "captcha verification failed: {:?}",
-            captcha_resp.error_codes
-        )));
-    }
-
-    let request_to_openai = RequestToOpenAI::new(prompt, user_request.question.clone(), timezone)?;
-    server.send(&StreamItem::Start(request_to_openai.max_tokens))?;
-
-    let auth_text = "Bearer ".to_string() + openai_key;
-
-    let mut headers = Headers::new();
-    headers.append("Content-Type", "application/json")?;
-    headers.append("Authorization", &auth_text)?;
-
-    let mut init = RequestInit::new();
-    init.with_method(Method::Post);
-    init.with_headers(headers);
-    let body = serde_json::to_string(&request_to_openai)?;
-    init.with_body(Some(JsValue::from_str(&body)));
-
-    let request = Request::new_with_init("https://api.openai.com/v1/chat/completions", &init)?;
-
-    let mut response = Fetch::Request(request).send().await?;
-    if response.status_code() != 200 {
-        return Err(error::Error::OpenAIError(
-            response.status_code(),
-            response.text().await?,
-        ));
-    }
-    let body = response.stream()?;
-
-    let mut json_stream = stream_parser::ChatStreamParser::parse_byte_stream(body);
-    let mut chatbot_answer = String::new();
-
-    while let Some(msg) = json_stream.next().await {
-        match msg {
-            Err(_) => {
-                server.send(&StreamItem::Finish(
-                    stream_parser::FinishReason::Unavailable,
-                ))?;
-            }
-            Ok(StreamItem::RoleMsg) => continue,
-            Ok(msg) => {
-                if let StreamItem::Delta(delta) = &msg {
-                    chatbot_answer.push_str(&delta);
-                }
-                server.send(&msg)?
-            }
-        }
-    }
-
-    // create an ID for this chat
-    let id = id::make_id();
-    let end_message = EndMessage { id: id.clone() };
-    let timestamp = id::get_utc_timestamp_sec();
-
-    // log the chat to KV
-    log_kv
-        .put(
-            &id,
-            &LogKvEntry::new(
-                user_request.question,
-                chatbot_answer,
-                remote_ip.to_string(),
-                location.to_string(),
-                timestamp,
-            ),
-        )?
-        .execute()
-        .await?;
-
-    // send the end message
-    server.send(&end_message)?;
-
-    Ok(())
-}
-
 pub async fn handle_chat(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    req.cf().timezone();
     let upgrade_header = req.headers().get("Upgrade")?;
     match upgrade_header {
         Some(x) if x == "websocket" => {
@@ -281,73 +211,103 @@ pub async fn handle_chat(req: Request, ctx: RouteContext<()>) -> Result<Response
         }
     }
 
+    let chat = ChatContext::from_request(&req, &ctx).await?;
+
     let ws_pair = WebSocketPair::new()?;
     let server = ws_pair.server;
     let client = ws_pair.client;
     server.accept()?;
 
-    let openai_key = ctx.var("OPENAI_API_KEY")?.to_string();
-    let turnstile_secret_key = ctx.var("TURNSTILE_SECRET_KEY")?.to_string();
-    let remote_ip = req.headers().get("CF-Connecting-IP")?.unwrap();
-    let cf = req.cf();
-    let location = format!(
-        "{} - {} - {} - {:?}",
-        cf.colo(),
-        cf.country().unwrap_or_else(|| "unknown".to_string()),
-        cf.city().unwrap_or_else(|| "unknown".to_string()),
-        cf.coordinates().unwrap_or_else(|| (0., 0.)),
-    );
-    let timezone = cf.timezone();
+    spawn_local(async move {
+        if let Err(e) = chat::serve_chat_in_ws(&chat, &server).await {
+            console_log!("error: {:?}", e);
+            server.send(&StreamItem::from(e)).unwrap();
+        }
 
-    let config = read_config(&ctx).await?;
-    let prompt = config.prompt;
+        server.close(Some(1000), Some("done")).unwrap();
+    });
 
-    let log_kv = ctx.kv(KV_LOG_BINDING)?;
+    let mut resp = Response::from_websocket(client)?;
+    attach_origin_header_to_resp(&req, &mut resp)?;
+    Ok(resp)
+}
 
+/// Same as `handle_chat`, for clients that can't use WebSockets: the question is the body, and
+/// the events are streamed back as `text/event-stream`. One question per request.
+pub async fn handle_chat_sse(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let user_request = serde_json::from_str::<UserRequest>(&req.text().await?)
+        .map_err(|e| error::Error::InvalidRequest(format!("malformed question: {}", e)))?;
+    let chat = ChatContext::from_request(&req, &ctx).await?;
+
+    let (sender, receiver) = futures_channel::mpsc::unbounded::<String>();
     spawn_local(async move {
-        let server_clone = server.clone();
-
-        match serve_chat_in_ws(
-            &openai_key,
-            &turnstile_secret_key,
-            &remote_ip,
-            &location,
-            timezone,
-            server,
-            prompt,
-            &log_kv,
-        )
-        .await
-        {
-            Ok(_) => {}
-            Err(e) => {
-                console_log!("error: {:?}", e);
-                server_clone.send(&StreamItem::from(e)).unwrap();
-            }
+        let sink = SseSink(sender);
+        let mut conversation = Conversation::default();
+        if let Err(e) = chat::answer(&chat, &mut conversation, user_request, &sink).await {
+            console_log!("error: {:?}", e);
+            // the client may be gone already
+            sink.send(&StreamItem::from(e)).ok();
         }
-
-        server_clone.close(Some(1000), Some("done")).unwrap();
     });
 
-    let mut resp = Response::from_websocket(client)?;
+    let body = receiver.map(|event| Ok::<_, worker::Error>(event.into_bytes()));
+    let mut resp = Response::from_stream(body)?;
+    let headers = resp.headers_mut();
+    headers.set("Content-Type", "text/event-stream")?;
+    headers.set("Cache-Control", "no-cache")?;
     attach_origin_header_to_resp(&req, &mut resp)?;
     Ok(resp)
 }
 
+/// Answer one question with a single JSON body, for integrations that don't stream.
+pub async fn handle_ask(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let user_request = serde_json::from_str::<UserRequest>(&req.text().await?)
+        .map_err(|e| error::Error::InvalidRequest(format!("malformed question: {}", e)))?;
+    let chat = ChatContext::from_request(&req, &ctx).await?;
+
+    let mut conversation = Conversation::default();
+    let answer = chat::answer(&chat, &mut conversation, user_request, &NullSink)
+        .await?
+        .ok_or_else(|| error::Error::Unavailable(chat.config.budget.exhausted_message()))?;
+
+    let mut resp = Response::from_json(&json!({
+        "id": answer.end.id,
+        "answer": answer.answer,
+        "finish_reason": answer.finish_reason,
+        "usage": answer.usage,
+        "session_token": answer.end.session_token,
+        "deletion_secret": answer.end.deletion_secret,
+    }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
 pub async fn handle_hint(req: Request, ctx: RouteContext<()>) -> Result<Response> {
     let config = read_config(&ctx).await?;
     let mut questions = config.questions;
 
-    const NUM_QUESTIONS_SAMPLED: usize = 3;
     let seed = Date::now().as_millis();
     let mut rng = rand_xorshift::XorShiftRng::seed_from_u64(seed);
     questions.shuffle(&mut rng);
-    let sampled_questions = &questions[..NUM_QUESTIONS_SAMPLED];
+    // configs saved before validation existed may have fewer questions
+    let sampled_questions = &questions[..NUM_QUESTIONS_SAMPLED.min(questions.len())];
+
+    // let visitors know up front that chat is closed for the day
+    let mut announcement = config.announcement;
+    if config.budget.has_limits() {
+        let kv = ctx.kv(KV_BINDING)?;
+        if config
+            .budget
+            .is_exhausted(&usage::get_today_usage(&kv).await?)
+        {
+            announcement = Some(config.budget.exhausted_message());
+        }
+    }
 
     let mut resp = Response::from_json(&json!({
         "welcome": config.welcome,
         "suggested_questions": sampled_questions,
-        "announcement": config.announcement,
+        "announcement": announcement,
     }))?;
     attach_origin_to_header(&req, resp.headers_mut())?;
     Ok(resp)
@@ -372,12 +332,7 @@ pub async fn handle_lookup(req: Request, ctx: RouteContext<()>) -> Result<Respon
         .1
         .to_string();
 
-    let log_kv = ctx.kv(KV_LOG_BINDING)?;
-    let entry = log_kv
-        .get(&id)
-        .json::<LogKvEntry>()
-        .await?
-        .ok_or_else(|| error::Error::NotFound(format!("No chat found with id {}", id)))?;
+    let entry = share::lookup(&req, &ctx, &id).await?;
 
     let mut resp = Response::from_json(&json!({
         "question": entry.question,
@@ -410,6 +365,14 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> WorkerResult
             let result = handle_chat(req, ctx).await;
             Ok(result_to_response(result))
         })
+        .post_async("/api/salieri/chat/sse", |req, ctx| async move {
+            let result = handle_chat_sse(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .post_async("/api/salieri/ask", |req, ctx| async move {
+            let result = handle_ask(req, ctx).await;
+            Ok(result_to_response(result))
+        })
         .get_async("/api/salieri/hint", |req, ctx| async move {
             let result = handle_hint(req, ctx).await;
             Ok(result_to_response(result))
@@ -423,14 +386,121 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> WorkerResult
                 admin::handle_config_post(req, ctx).await;
             Ok(result_to_response(result))
         })
+        .get_async("/api/salieri/config/version", |req, ctx| async move {
+            let result = admin::handle_config_version_get(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .get_async("/api/salieri/config/backups", |req, ctx| async move {
+            let result = admin::handle_config_backups_list(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .get_async("/api/salieri/config/backup", |req, ctx| async move {
+            let result = admin::handle_config_backup_get(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .get_async("/api/salieri/config/backup/diff", |req, ctx| async move {
+            let result = admin::handle_config_backup_diff(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .post_async(
+            "/api/salieri/config/backup/restore",
+            |req, ctx| async move {
+                let result = admin::handle_config_backup_restore(req, ctx).await;
+                Ok(result_to_response(result))
+            },
+        )
         .get_async("/api/salieri/lookup", |req, ctx| async move {
             let result = handle_lookup(req, ctx).await;
             Ok(result_to_response(result))
         })
+        .get_async("/api/salieri/logs", |req, ctx| async move {
+            let result = logs::handle_logs_list(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .get_async("/api/salieri/logs/export", |req, ctx| async move {
+            let result = logs::handle_logs_export(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .post_async("/api/salieri/apikeys", |req, ctx| async move {
+            let result = apikey::handle_api_key_mint(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .get_async("/api/salieri/apikeys", |req, ctx| async move {
+            let result = apikey::handle_api_keys_list(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .post_async("/api/salieri/apikeys/revoke", |req, ctx| async move {
+            let result = apikey::handle_api_key_revoke(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .post_async("/api/salieri/share", |req, ctx| async move {
+            let result = share::handle_share(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .get_async("/api/salieri/share/:id", |req, ctx| async move {
+            let result = share_page::handle_share_page(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .post_async("/api/salieri/chat/delete", |req, ctx| async move {
+            let result = share::handle_delete(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .post_async("/api/salieri/chat/takedown", |req, ctx| async move {
+            let result = share::handle_takedown(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .post_async("/api/salieri/feedback", |req, ctx| async move {
+            let result = feedback::handle_feedback(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .get_async("/api/salieri/feedback/stats", |req, ctx| async move {
+            let result = feedback::handle_feedback_stats(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .post_async("/api/salieri/sessions/revoke", |req, ctx| async move {
+            let result = session::handle_session_revoke(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .get_async("/api/salieri/sessions/revoked", |req, ctx| async move {
+            let result = session::handle_sessions_revoked(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .post_async("/api/salieri/cache/invalidate", |req, ctx| async move {
+            let result = cache::handle_cache_invalidate(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .get_async("/api/salieri/knowledge", |req, ctx| async move {
+            let result = knowledge::handle_knowledge_list(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .post_async("/api/salieri/knowledge", |req, ctx| async move {
+            let result = knowledge::handle_knowledge_upload(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .post_async("/api/salieri/knowledge/delete", |req, ctx| async move {
+            let result = knowledge::handle_knowledge_delete(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .post_async("/api/salieri/knowledge/reindex", |req, ctx| async move {
+            let result = knowledge::handle_knowledge_reindex(req, ctx).await;
+            Ok(result_to_response(result))
+        })
+        .get_async("/api/salieri/experiment/stats", |req, ctx| async move {
+            let result = experiment::handle_experiment_stats(req, ctx).await;
+            Ok(result_to_response(result))
+        })
         .options_async("/api/salieri/:any", |req, _| async move {
             let result = handle_options(req);
             Ok(result_to_response(result))
         })
+        .options_async("/api/salieri/chat/sse", |req, _| async move {
+            let result = handle_options(req);
+            Ok(result_to_response(result))
+        })
+        .options_async("/api/salieri/chat/delete", |req, _| async move {
+            let result = handle_options(req);
+            Ok(result_to_response(result))
+        })
         .run(req, env)
         .await
 }
@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use worker::{kv::KvStore, Request, Response, RouteContext};
+
+use crate::admin::verify_read_access;
+use crate::constants::*;
+use crate::error::{Error, Result};
+use crate::feedback::Rating;
+use crate::{attach_origin_to_header, LogKvEntry};
+
+const DEFAULT_PAGE_SIZE: u64 = 20;
+const MAX_PAGE_SIZE: u64 = 100;
+/// every entry read is a KV subrequest, of which an invocation gets about 1000
+const DEFAULT_EXPORT_PAGE_SIZE: u64 = 500;
+const MAX_EXPORT_PAGE_SIZE: u64 = 900;
+/// response header of an export with the cursor of the next page, absent on the last page
+const NEXT_CURSOR_HEADER: &str = "X-Next-Cursor";
+
+/// Narrows down the log entries returned to the admin. Every condition is optional.
+#[derive(Debug, Default)]
+struct LogFilter {
+    /// case-insensitive substring of the location, which holds colo, country and city
+    location: Option<String>,
+    /// case-insensitive substring of the question
+    question: Option<String>,
+    /// inclusive bounds on the timestamp, in seconds
+    from: Option<i64>,
+    to: Option<i64>,
+    /// `Some(None)` for entries with any feedback, `Some(Some(rating))` for entries rated so
+    feedback: Option<Option<Rating>>,
+    /// version of the config the answer was given under
+    config_version: Option<u32>,
+}
+
+fn parse_timestamp(params: &HashMap<String, String>, name: &str) -> Result<Option<i64>> {
+    params
+        .get(name)
+        .map(|v| {
+            v.parse::<i64>().map_err(|_| {
+                Error::InvalidRequest(format!("`{}` must be a unix timestamp in seconds", name))
+            })
+        })
+        .transpose()
+}
+
+impl LogFilter {
+    fn from_params(params: &HashMap<String, String>) -> Result<Self> {
+        Ok(Self {
+            location: params.get("location").map(|v| v.to_lowercase()),
+            question: params.get("q").map(|v| v.to_lowercase()),
+            from: parse_timestamp(params, "from")?,
+            to: parse_timestamp(params, "to")?,
+            feedback: match params.get("feedback").map(String::as_str) {
+                None => None,
+                Some("any") => Some(None),
+                Some(rating) => Some(Some(
+                    serde_json::from_value(serde_json::json!(rating)).map_err(|_| {
+                        Error::InvalidRequest("`feedback` must be up, down or any".to_string())
+                    })?,
+                )),
+            },
+            config_version: params
+                .get("config_version")
+                .map(|v| {
+                    v.parse::<u32>().map_err(|_| {
+                        Error::InvalidRequest("`config_version` must be a number".to_string())
+                    })
+                })
+                .transpose()?,
+        })
+    }
+
+    fn matches(&self, entry: &LogKvEntry) -> bool {
+        let contains = |haystack: &str, needle: &Option<String>| match needle {
+            Some(needle) => haystack.to_lowercase().contains(needle),
+            None => true,
+        };
+        contains(&entry.location, &self.location)
+            && contains(&entry.question, &self.question)
+            && self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp <= to)
+            && self.feedback.is_none_or(|rating| {
+                entry
+                    .feedback
+                    .as_ref()
+                    .is_some_and(|feedback| rating.is_none_or(|rating| feedback.rating == rating))
+            })
+            && self
+                .config_version
+                .is_none_or(|version| entry.config_version == Some(version))
+    }
+}
+
+#[derive(Serialize)]
+struct LogItem {
+    id: String,
+    #[serde(flatten)]
+    entry: LogKvEntry,
+}
+
+struct LogPage {
+    items: Vec<LogItem>,
+    cursor: Option<String>,
+    list_complete: bool,
+}
+
+fn query_params(req: &Request) -> Result<HashMap<String, String>> {
+    Ok(req.url()?.query_pairs().into_owned().collect())
+}
+
+/// Log ids start with the UTC date, so any prefix of `YYYY-MM-DD` selects a day, month or year.
+fn date_prefix(params: &HashMap<String, String>) -> Result<String> {
+    let date = params
+        .get("date")
+        .ok_or_else(|| Error::InvalidRequest("Expected query parameter `date`".to_string()))?;
+    if date.is_empty() || !date.chars().all(|c| c.is_ascii_digit() || c == '-') {
+        return Err(Error::InvalidRequest(format!(
+            "`date` must look like YYYY-MM-DD, got {}",
+            date
+        )));
+    }
+    Ok(date.clone())
+}
+
+/// `?limit=`, clamped to `1..=max`.
+fn page_size(params: &HashMap<String, String>, default: u64, max: u64) -> Result<u64> {
+    match params.get("limit") {
+        Some(limit) => Ok(limit
+            .parse::<u64>()
+            .map_err(|_| Error::InvalidRequest("`limit` must be a number".to_string()))?
+            .clamp(1, max)),
+        None => Ok(default),
+    }
+}
+
+/// Scan one page of KV keys under `prefix`. Entries not matching `filter` are dropped, so a page
+/// may hold fewer than `limit` items even if there are more to come.
+async fn read_log_page(
+    log_kv: &KvStore,
+    prefix: &str,
+    cursor: Option<String>,
+    limit: u64,
+    filter: &LogFilter,
+) -> Result<LogPage> {
+    let mut list = log_kv.list().prefix(prefix.to_string()).limit(limit);
+    if let Some(cursor) = cursor {
+        list = list.cursor(cursor);
+    }
+    let page = list.execute().await?;
+
+    let mut items = Vec::new();
+    for key in page.keys {
+        let entry = match log_kv.get(&key.name).json::<LogKvEntry>().await? {
+            Some(entry) => entry,
+            // deleted since listing
+            None => continue,
+        };
+        if filter.matches(&entry) {
+            items.push(LogItem {
+                id: key.name,
+                entry,
+            });
+        }
+    }
+    Ok(LogPage {
+        items,
+        cursor: page.cursor,
+        list_complete: page.list_complete,
+    })
+}
+
+pub async fn handle_logs_list(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    verify_read_access(&req, &ctx).await?;
+
+    let params = query_params(&req)?;
+    let prefix = date_prefix(&params)?;
+    let filter = LogFilter::from_params(&params)?;
+    let limit = page_size(&params, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE)?;
+
+    let log_kv = ctx.kv(KV_LOG_BINDING)?;
+    let page = read_log_page(
+        &log_kv,
+        &prefix,
+        params.get("cursor").cloned(),
+        limit,
+        &filter,
+    )
+    .await?;
+
+    let mut resp = Response::from_json(&serde_json::json!({
+        "entries": page.items,
+        "cursor": page.cursor,
+        "list_complete": page.list_complete,
+    }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+/// Matching entries under the date prefix, one JSON object per line. A page scans at most
+/// `?limit=` keys; the cursor of the next one is in the `X-Next-Cursor` header.
+pub async fn handle_logs_export(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    verify_read_access(&req, &ctx).await?;
+
+    let params = query_params(&req)?;
+    let prefix = date_prefix(&params)?;
+    let filter = LogFilter::from_params(&params)?;
+    let limit = page_size(&params, DEFAULT_EXPORT_PAGE_SIZE, MAX_EXPORT_PAGE_SIZE)?;
+
+    let log_kv = ctx.kv(KV_LOG_BINDING)?;
+    let page = read_log_page(
+        &log_kv,
+        &prefix,
+        params.get("cursor").cloned(),
+        limit,
+        &filter,
+    )
+    .await?;
+    let mut jsonl = String::new();
+    for item in page.items {
+        jsonl.push_str(&serde_json::to_string(&item)?);
+        jsonl.push('\n');
+    }
+
+    let mut resp = Response::ok(jsonl)?;
+    if let (false, Some(cursor)) = (page.list_complete, &page.cursor) {
+        resp.headers_mut().set(NEXT_CURSOR_HEADER, cursor)?;
+    }
+    resp.headers_mut()
+        .set("Access-Control-Expose-Headers", NEXT_CURSOR_HEADER)?;
+    resp.headers_mut()
+        .set("Content-Type", "application/x-ndjson")?;
+    resp.headers_mut().set(
+        "Content-Disposition",
+        &format!("attachment; filename=\"salieri-logs-{}.jsonl\"", prefix),
+    )?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feedback::Feedback;
+
+    fn entry(question: &str, location: &str, timestamp: i64) -> LogKvEntry {
+        LogKvEntry::new(
+            question.to_string(),
+            "answer".to_string(),
+            "127.0.0.1".to_string(),
+            location.to_string(),
+            timestamp,
+            "2023-05-01-conversation".to_string(),
+            0,
+        )
+    }
+
+    #[test]
+    fn filter_entries() {
+        let params: HashMap<String, String> = [
+            ("location", "sjc"),
+            ("q", "stanford"),
+            ("from", "100"),
+            ("to", "200"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let filter = LogFilter::from_params(&params).unwrap();
+
+        let location = "SJC - US - San Jose - (37.3, -121.9)";
+        assert!(filter.matches(&entry("Why Stanford?", location, 150)));
+        assert!(!filter.matches(&entry("Why Stanford?", "NRT - JP - Tokyo", 150)));
+        assert!(!filter.matches(&entry("What do you do?", location, 150)));
+        assert!(!filter.matches(&entry("Why Stanford?", location, 201)));
+
+        assert!(LogFilter::default().matches(&entry("anything", "anywhere", 0)));
+    }
+
+    #[test]
+    fn filter_feedback() {
+        let filter = |value: &str| {
+            let params: HashMap<String, String> = vec![("feedback".to_string(), value.to_string())]
+                .into_iter()
+                .collect();
+            LogFilter::from_params(&params).unwrap()
+        };
+        let mut rated = entry("Why Stanford?", "SJC", 150);
+        rated.feedback = Some(Feedback {
+            rating: Rating::Down,
+            comment: None,
+            tags: Vec::new(),
+            timestamp: 160,
+        });
+        let unrated = entry("Why Stanford?", "SJC", 150);
+
+        assert!(filter("any").matches(&rated));
+        assert!(!filter("any").matches(&unrated));
+        assert!(filter("down").matches(&rated));
+        assert!(!filter("up").matches(&rated));
+    }
+
+    #[test]
+    fn filter_config_version() {
+        let params: HashMap<String, String> = vec![("config_version".to_string(), "3".to_string())]
+            .into_iter()
+            .collect();
+        let filter = LogFilter::from_params(&params).unwrap();
+        let mut entry = entry("Why Stanford?", "SJC", 150);
+        assert!(!filter.matches(&entry));
+        entry.config_version = Some(3);
+        assert!(filter.matches(&entry));
+        entry.config_version = Some(4);
+        assert!(!filter.matches(&entry));
+    }
+
+    #[test]
+    fn page_sizes() {
+        let params = |limit: &str| -> HashMap<String, String> {
+            vec![("limit".to_string(), limit.to_string())]
+                .into_iter()
+                .collect()
+        };
+        assert_eq!(page_size(&HashMap::new(), 500, 900).unwrap(), 500);
+        assert_eq!(page_size(&params("5000"), 500, 900).unwrap(), 900);
+        assert_eq!(page_size(&params("0"), 500, 900).unwrap(), 1);
+        assert!(page_size(&params("all"), 500, 900).is_err());
+    }
+
+    #[test]
+    fn reject_bad_params() {
+        let params: HashMap<String, String> = [("date", "2023-05-01*"), ("from", "yesterday")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert!(date_prefix(&params).is_err());
+        assert!(LogFilter::from_params(&params).is_err());
+    }
+}
@@ -1,5 +1,19 @@
+use crate::cache::{CacheConfig, MAX_TTL_HOURS};
+use crate::captcha::{CaptchaConfig, CaptchaProvider};
+use crate::constants::NUM_QUESTIONS_SAMPLED;
 use crate::error::{Error, Result};
+use crate::experiment::{is_variant_name, ExperimentConfig};
+use crate::input::InputLimits;
+use crate::knowledge::KnowledgeConfig;
+use crate::provider::{ProviderConfig, Target};
+use crate::ratelimit::RateLimitConfig;
+use crate::session::{SessionConfig, MAX_TTL_MINUTES};
+use crate::template::{is_variable_name, Template, BUILTIN_VARIABLES};
+use crate::tools::{ToolsConfig, MAX_ITERATIONS_LIMIT};
+use crate::usage::BudgetConfig;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum Role {
@@ -25,11 +39,451 @@ pub struct Prompt {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConversationConfig {
+    /// maximum number of questions answered over one websocket connection
+    pub max_turns: u32,
+    /// maximum number of completion tokens generated over one websocket connection
+    pub max_tokens: u32,
+}
+
+impl Default for ConversationConfig {
+    fn default() -> Self {
+        // one question per connection, which is the behavior before multi-turn support
+        Self {
+            max_turns: 1,
+            max_tokens: 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Timeouts {
+    /// move on to the next target if the first delta doesn't arrive within this many milliseconds
+    pub first_token_ms: Option<u64>,
+    /// end the answer if the upstream is silent for this many milliseconds after the first delta
+    pub between_tokens_ms: Option<u64>,
+    /// end the answer this many milliseconds after the question, however far it got
+    pub total_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Config {
     pub prompt: Prompt,
     pub questions: Vec<String>,
     pub welcome: String,
     pub announcement: Option<String>,
+    #[serde(default)]
+    pub conversation: ConversationConfig,
+    /// upstream API serving `prompt.model`, OpenAI if omitted
+    #[serde(default)]
+    pub provider: ProviderConfig,
+    /// tried in order when the previous target answers with 429 or 5xx, or times out
+    #[serde(default)]
+    pub fallbacks: Vec<Target>,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// Turnstile if omitted
+    #[serde(default)]
+    pub captcha: CaptchaConfig,
+    /// how long, and for how many questions, a solved captcha lasts
+    #[serde(default)]
+    pub session: SessionConfig,
+    #[serde(default)]
+    pub budget: BudgetConfig,
+    /// what questions are accepted, and how they are cleaned up
+    #[serde(default)]
+    pub limits: InputLimits,
+    /// documents retrieved into [KNOWLEDGE], none if omitted
+    #[serde(default)]
+    pub knowledge: Option<KnowledgeConfig>,
+    /// tools the model can call, none if omitted
+    #[serde(default)]
+    pub tools: Option<ToolsConfig>,
+    /// answers kept for repeated first questions, none if omitted
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+    /// prompt variants tried on different visitors, none if omitted
+    #[serde(default)]
+    pub experiment: Option<ExperimentConfig>,
+    /// value of [SITE_NAME] in prompt messages
+    #[serde(default)]
+    pub site_name: Option<String>,
+    /// extra variables for prompt messages, e.g. `GREETING = "Hi"` for [GREETING]
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+    /// bumped every time the config is saved; 0 for a config never saved through the API
+    #[serde(default)]
+    pub version: u32,
+}
+
+/// A problem with one field of a config.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Upper bound for `max_tokens`, to catch typos rather than to match any model's context size.
+const MAX_TOKENS_LIMIT: u32 = 4096;
+
+impl Config {
+    /// Short hash of the config as JSON, which changes with any field but `version`.
+    pub fn hash(&self) -> String {
+        // a restored config hashes like the version it was restored from
+        let content = Config {
+            version: 0,
+            ..self.clone()
+        };
+        let json = serde_json::to_vec(&content).expect("config serializes");
+        hex::encode(&Sha256::digest(json)[..6])
+    }
+
+    /// Check everything that would otherwise only fail at chat time. Returns all problems found.
+    pub fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+
+        if self.prompt.model.trim().is_empty() {
+            errors.push(FieldError::new("prompt.model", "model must not be empty"));
+        }
+        for (i, fallback) in self.fallbacks.iter().enumerate() {
+            if fallback.model.trim().is_empty() {
+                errors.push(FieldError::new(
+                    format!("fallbacks[{}].model", i),
+                    "model must not be empty",
+                ));
+            }
+        }
+
+        self.validate_messages("prompt.messages", &self.prompt.messages, &mut errors);
+        for name in self.variables.keys() {
+            if BUILTIN_VARIABLES.contains(&name.as_str()) {
+                errors.push(FieldError::new(
+                    format!("variables.{}", name),
+                    "built-in variables can't be overridden",
+                ));
+            } else if !is_variable_name(name) {
+                errors.push(FieldError::new(
+                    format!("variables.{}", name),
+                    "names must be UPPER_SNAKE_CASE",
+                ));
+            }
+        }
+
+        if let Some(max_tokens) = self.prompt.max_tokens {
+            if max_tokens == 0 || max_tokens > MAX_TOKENS_LIMIT {
+                errors.push(FieldError::new(
+                    "prompt.max_tokens",
+                    format!("max_tokens must be between 1 and {}", MAX_TOKENS_LIMIT),
+                ));
+            }
+        }
+
+        if self.questions.len() < NUM_QUESTIONS_SAMPLED {
+            errors.push(FieldError::new(
+                "questions",
+                format!(
+                    "at least {} questions are needed, got {}",
+                    NUM_QUESTIONS_SAMPLED,
+                    self.questions.len()
+                ),
+            ));
+        }
+
+        for (field, value) in [
+            ("timeouts.first_token_ms", self.timeouts.first_token_ms),
+            (
+                "timeouts.between_tokens_ms",
+                self.timeouts.between_tokens_ms,
+            ),
+            ("timeouts.total_ms", self.timeouts.total_ms),
+        ]
+        .iter()
+        {
+            if *value == Some(0) {
+                errors.push(FieldError::new(*field, "timeouts must be at least 1 ms"));
+            }
+        }
+
+        if !(0.0..=1.0).contains(&self.captcha.min_score) {
+            errors.push(FieldError::new(
+                "captcha.min_score",
+                "min_score must be between 0.0 and 1.0",
+            ));
+        }
+        if self.captcha.provider == CaptchaProvider::Mock {
+            // it would let anyone in
+            errors.push(FieldError::new(
+                "captcha.provider",
+                "the mock can only be selected with the CAPTCHA_PROVIDER env var",
+            ));
+        }
+        if self.captcha.provider == CaptchaProvider::HCaptcha && self.captcha.action.is_some() {
+            errors.push(FieldError::new(
+                "captcha.action",
+                "hCaptcha doesn't report actions",
+            ));
+        }
+
+        if self.session.ttl_minutes == 0 || self.session.ttl_minutes > MAX_TTL_MINUTES {
+            errors.push(FieldError::new(
+                "session.ttl_minutes",
+                format!("ttl_minutes must be between 1 and {}", MAX_TTL_MINUTES),
+            ));
+        }
+        if self.session.max_questions == 0 {
+            errors.push(FieldError::new(
+                "session.max_questions",
+                "max_questions must be at least 1",
+            ));
+        }
+
+        if self.limits.max_chars == 0 {
+            errors.push(FieldError::new(
+                "limits.max_chars",
+                "max_chars must be at least 1",
+            ));
+        }
+        if self.limits.max_lines == Some(0) {
+            errors.push(FieldError::new(
+                "limits.max_lines",
+                "max_lines must be at least 1",
+            ));
+        }
+
+        if let Some(knowledge) = &self.knowledge {
+            if self
+                .provider
+                .build()
+                .embeddings_endpoint(&knowledge.embedding_model)
+                .is_none()
+            {
+                errors.push(FieldError::new(
+                    "knowledge.embedding_model",
+                    format!("{} has no embeddings API", self.provider.name()),
+                ));
+            }
+            if knowledge.top_k == 0 {
+                errors.push(FieldError::new(
+                    "knowledge.top_k",
+                    "top_k must be at least 1",
+                ));
+            }
+            if knowledge.chunk_overlap >= knowledge.chunk_chars {
+                errors.push(FieldError::new(
+                    "knowledge.chunk_overlap",
+                    "chunk_overlap must be less than chunk_chars",
+                ));
+            }
+            if !self
+                .prompt
+                .messages
+                .iter()
+                .any(|message| message.content.contains("[KNOWLEDGE]"))
+            {
+                errors.push(FieldError::new(
+                    "knowledge",
+                    "no prompt message has a [KNOWLEDGE] placeholder",
+                ));
+            }
+        }
+
+        if let Some(tools) = &self.tools {
+            for (i, target) in self.targets().iter().enumerate() {
+                if !target.provider.build().supports_tools() {
+                    let field = match i {
+                        0 => "provider".to_string(),
+                        i => format!("fallbacks[{}].provider", i - 1),
+                    };
+                    errors.push(FieldError::new(
+                        field,
+                        format!("{} doesn't support tools", target.provider.name()),
+                    ));
+                }
+            }
+            if tools.max_iterations == 0 || tools.max_iterations > MAX_ITERATIONS_LIMIT {
+                errors.push(FieldError::new(
+                    "tools.max_iterations",
+                    format!(
+                        "max_iterations must be between 1 and {}",
+                        MAX_ITERATIONS_LIMIT
+                    ),
+                ));
+            }
+            if let Some(url) = &tools.blog_feed_url {
+                if !url.starts_with("https://") && !url.starts_with("http://") {
+                    errors.push(FieldError::new(
+                        "tools.blog_feed_url",
+                        "blog_feed_url must be an http(s) URL",
+                    ));
+                }
+            }
+            for (i, entry) in tools.faq.iter().enumerate() {
+                if entry.question.trim().is_empty() || entry.answer.trim().is_empty() {
+                    errors.push(FieldError::new(
+                        format!("tools.faq[{}]", i),
+                        "questions and answers must not be empty",
+                    ));
+                }
+            }
+        }
+
+        if let Some(cache) = &self.cache {
+            if cache.ttl_hours == 0 || cache.ttl_hours > MAX_TTL_HOURS {
+                errors.push(FieldError::new(
+                    "cache.ttl_hours",
+                    format!("ttl_hours must be between 1 and {}", MAX_TTL_HOURS),
+                ));
+            }
+        }
+
+        if let Some(experiment) = &self.experiment {
+            if experiment.seed.trim().is_empty() {
+                errors.push(FieldError::new("experiment.seed", "seed must not be empty"));
+            }
+            if experiment
+                .variants
+                .iter()
+                .all(|variant| variant.weight == 0)
+            {
+                errors.push(FieldError::new(
+                    "experiment.variants",
+                    "at least one variant needs a weight above 0",
+                ));
+            }
+            for (i, variant) in experiment.variants.iter().enumerate() {
+                let field = format!("experiment.variants[{}]", i);
+                if !is_variant_name(&variant.name) {
+                    errors.push(FieldError::new(
+                        format!("{}.name", field),
+                        "names must be lowercase letters, digits, `-` and `_`",
+                    ));
+                } else if experiment.variants[..i]
+                    .iter()
+                    .any(|other| other.name == variant.name)
+                {
+                    errors.push(FieldError::new(
+                        format!("{}.name", field),
+                        format!("variant {} is defined twice", variant.name),
+                    ));
+                }
+                if variant
+                    .model
+                    .as_deref()
+                    .is_some_and(|model| model.trim().is_empty())
+                {
+                    errors.push(FieldError::new(
+                        format!("{}.model", field),
+                        "model must not be empty",
+                    ));
+                }
+                if let Some(messages) = &variant.messages {
+                    self.validate_messages(&format!("{}.messages", field), messages, &mut errors);
+                }
+                if let Some(max_tokens) = variant.max_tokens {
+                    if max_tokens == 0 || max_tokens > MAX_TOKENS_LIMIT {
+                        errors.push(FieldError::new(
+                            format!("{}.max_tokens", field),
+                            format!("max_tokens must be between 1 and {}", MAX_TOKENS_LIMIT),
+                        ));
+                    }
+                }
+            }
+        }
+
+        if self.conversation.max_turns == 0 {
+            errors.push(FieldError::new(
+                "conversation.max_turns",
+                "max_turns must be at least 1",
+            ));
+        }
+        if self.conversation.max_tokens == 0 {
+            errors.push(FieldError::new(
+                "conversation.max_tokens",
+                "max_tokens must be at least 1",
+            ));
+        }
+
+        errors
+    }
+
+    /// Check that `messages` start with a system message and only use known placeholders.
+    fn validate_messages(&self, field: &str, messages: &[Message], errors: &mut Vec<FieldError>) {
+        match messages.first() {
+            Some(message) if message.role == Role::System => {}
+            _ => errors.push(FieldError::new(
+                format!("{}[0]", field),
+                "first message must be a system message",
+            )),
+        }
+        for (i, message) in messages.iter().enumerate() {
+            let field = format!("{}[{}].content", field, i);
+            match Template::parse(&message.content) {
+                Ok(template) => {
+                    for name in template.variables() {
+                        if !BUILTIN_VARIABLES.contains(&name) && !self.variables.contains_key(name)
+                        {
+                            errors.push(FieldError::new(
+                                field.clone(),
+                                format!("unknown placeholder [{}]", name),
+                            ));
+                        }
+                    }
+                }
+                Err(message) => errors.push(FieldError::new(field, message)),
+            }
+        }
+    }
+
+    /// `prompt.model` served by `provider`, followed by the fallbacks.
+    pub fn targets(&self) -> Vec<Target> {
+        let primary = Target {
+            provider: self.provider.clone(),
+            model: self.prompt.model.clone(),
+        };
+        std::iter::once(primary)
+            .chain(self.fallbacks.iter().cloned())
+            .collect()
+    }
+
+    /// Variables for prompt messages that stay the same during a visit. The current time is
+    /// added per question by `insert_time_variables`.
+    pub fn template_variables(&self, country: &str, city: &str) -> HashMap<String, String> {
+        let mut variables: HashMap<String, String> = self
+            .variables
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let questions = self
+            .questions
+            .iter()
+            .map(|q| format!("- {}", q))
+            .collect::<Vec<_>>()
+            .join("\n");
+        for (name, value) in [
+            ("COUNTRY", country.to_string()),
+            ("CITY", city.to_string()),
+            ("SITE_NAME", self.site_name.clone().unwrap_or_default()),
+            ("QUESTIONS", questions),
+            // filled in per question if there is a knowledge base
+            ("KNOWLEDGE", String::new()),
+        ]
+        .iter()
+        {
+            variables.insert(name.to_string(), value.clone());
+        }
+        variables
+    }
 }
 
 #[derive(Serialize, Debug, Clone, PartialEq, Eq)]
@@ -38,42 +492,78 @@ pub struct RequestToOpenAI {
     pub messages: Vec<Message>,
     pub max_tokens: u32,
     pub stream: bool, // true
+    /// definitions in OpenAI's format, see `ToolRegistry`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<serde_json::Value>,
+    /// `none` once the model has to answer without more tool calls
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<String>,
+    /// tool calls and their results so far, which go after the question
+    #[serde(skip)]
+    pub tool_messages: Vec<serde_json::Value>,
 }
 
-pub fn get_local_datetime(timezone: impl chrono::TimeZone) -> String {
-    use chrono::prelude::*;
+fn local_now<Tz: chrono::TimeZone>(timezone: Tz) -> chrono::DateTime<Tz> {
     let js_date = js_sys::Date::new_0();
     let now_timestamp = js_date.get_time() / 1000.; // convert milliseconds to seconds
     let naive_datetime =
         chrono::NaiveDateTime::from_timestamp_opt(now_timestamp as i64, 0).unwrap();
-    let local_datetime = timezone.from_utc_datetime(&naive_datetime);
-    format!(
-        "{}-{:02}-{:02} {}:{}:{}",
-        local_datetime.year(),
-        local_datetime.month(),
-        local_datetime.day(),
-        local_datetime.hour(),
-        local_datetime.minute(),
-        local_datetime.second()
-    )
+    timezone.from_utc_datetime(&naive_datetime)
+}
+
+/// Current UTC offset of `timezone`, which is all that is needed for the rest of a visit.
+pub fn utc_offset(timezone: impl chrono::TimeZone) -> chrono::FixedOffset {
+    use chrono::Offset;
+    local_now(timezone).offset().fix()
+}
+
+/// Set [CURRENT_TIME] and [CURRENT_DATE] to now in `timezone`.
+pub fn insert_time_variables(
+    variables: &mut HashMap<String, String>,
+    timezone: impl chrono::TimeZone,
+) {
+    use chrono::prelude::*;
+    let local_datetime = local_now(timezone);
+    variables.insert(
+        "CURRENT_DATE".to_string(),
+        format!(
+            "{}-{:02}-{:02}",
+            local_datetime.year(),
+            local_datetime.month(),
+            local_datetime.day()
+        ),
+    );
+    variables.insert(
+        "CURRENT_TIME".to_string(),
+        format!(
+            "{}-{:02}-{:02} {}:{}:{}",
+            local_datetime.year(),
+            local_datetime.month(),
+            local_datetime.day(),
+            local_datetime.hour(),
+            local_datetime.minute(),
+            local_datetime.second()
+        ),
+    );
 }
 
 impl RequestToOpenAI {
+    /// Build the request for `user_question`. `history` holds the previous questions and
+    /// answers of the same conversation, and is inserted between the prompt and the question.
+    /// Prompt messages are rendered with `variables`, see `template`. `user_question` is
+    /// expected to have passed `InputLimits::check` already.
     pub fn new(
         mut prompt: Prompt,
+        history: &[Message],
         user_question: String,
-        timezone: impl chrono::TimeZone,
+        variables: &HashMap<String, String>,
     ) -> Result<Self> {
-        // length check
-        const MAX_LENGTH: usize = 300; // TODO: make this configurable
-        if user_question.len() > MAX_LENGTH {
-            return Err(Error::InvalidRequest(format!(
-                "question is too long ({} > {})",
-                user_question.len(),
-                MAX_LENGTH
-            )));
+        for message in prompt.messages.iter_mut() {
+            let template = Template::parse(&message.content).map_err(Error::InternalError)?;
+            message.content = template.render(variables);
         }
 
+        prompt.messages.extend_from_slice(history);
         prompt.messages.push(Message {
             role: Role::User,
             content: user_question,
@@ -85,18 +575,14 @@ impl RequestToOpenAI {
             ));
         }
 
-        // replace [CURRENT_DATE] in first message with current date
-        let local_date = get_local_datetime(timezone);
-
-        prompt.messages[0].content = prompt.messages[0]
-            .content
-            .replace("[CURRENT_TIME]", &local_date);
-
         Ok(Self {
             model: prompt.model,
             messages: prompt.messages,
             max_tokens: prompt.max_tokens.unwrap_or(128),
             stream: true,
+            tools: Vec::new(),
+            tool_choice: None,
+            tool_messages: Vec::new(),
         })
     }
 }
@@ -105,6 +591,12 @@ impl RequestToOpenAI {
 pub struct UserRequest {
     pub question: String,
     pub captcha_token: Option<String>,
+    /// token from an earlier chat, accepted in place of a captcha
+    #[serde(default)]
+    pub session_token: Option<String>,
+    /// language of error messages, e.g. `zh-CN`; `Accept-Language` is used if omitted
+    #[serde(default)]
+    pub locale: Option<String>,
 }
 
 #[cfg(test)]
@@ -169,4 +661,262 @@ mod tests {
         let actual: Prompt = toml::from_str(toml).unwrap();
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn config_without_conversation() {
+        let json = r#"{
+            "prompt": {
+                "model": "gpt-3.5-turbo",
+                "messages": [{"role": "system", "content": "You are a helpful chatbot."}]
+            },
+            "questions": ["a", "b", "c"],
+            "welcome": "Hi!",
+            "announcement": null
+          }
+          "#;
+
+        let actual: Config = serde_json::from_str(json).unwrap();
+        assert_eq!(actual.conversation, ConversationConfig::default());
+    }
+
+    #[test]
+    fn config_targets() {
+        let json = r#"{
+            "prompt": {"model": "gpt-4", "messages": []},
+            "questions": [],
+            "welcome": "Hi!",
+            "announcement": null,
+            "fallbacks": [{"provider": {"kind": "anthropic"}, "model": "claude-3-haiku-20240307"}]
+          }
+          "#;
+
+        let config: Config = serde_json::from_str(json).unwrap();
+        let targets = config.targets();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[0].provider, ProviderConfig::OpenAI);
+        assert_eq!(targets[0].model, "gpt-4");
+        assert_eq!(targets[1].provider, ProviderConfig::Anthropic);
+    }
+
+    fn valid_config() -> Config {
+        serde_json::from_str(
+            r#"{
+                "prompt": {
+                    "model": "gpt-3.5-turbo",
+                    "messages": [{"role": "system", "content": "Today is [CURRENT_TIME]. See [my blog](https://tomshen.io)."}],
+                    "max_tokens": 200
+                },
+                "questions": ["a", "b", "c"],
+                "welcome": "Hi!",
+                "announcement": null
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn validate_config() {
+        assert_eq!(valid_config().validate(), vec![]);
+
+        let mut config = valid_config();
+        config.prompt.model = " ".to_string();
+        config.prompt.messages[0].role = Role::User;
+        config.prompt.messages[0].content = "Hello [VISITOR_NAME]".to_string();
+        config.prompt.max_tokens = Some(0);
+        config.questions.pop();
+        config.timeouts.total_ms = Some(0);
+        let fields: Vec<String> = config.validate().into_iter().map(|e| e.field).collect();
+        assert_eq!(
+            fields,
+            vec![
+                "prompt.model",
+                "prompt.messages[0]",
+                "prompt.messages[0].content",
+                "prompt.max_tokens",
+                "questions",
+                "timeouts.total_ms",
+            ]
+        );
+    }
+
+    #[test]
+    fn hash_ignores_version() {
+        let config = valid_config();
+        let mut saved = config.clone();
+        saved.version = 7;
+        assert_eq!(saved.hash(), config.hash());
+        saved.welcome = "Hello!".to_string();
+        assert_ne!(saved.hash(), config.hash());
+    }
+
+    #[test]
+    fn hash_is_stable() {
+        let json = r#"{
+            "prompt": {"model": "gpt-3.5-turbo", "messages": [{"role": "system", "content": "Hi"}]},
+            "questions": ["a", "b", "c"],
+            "welcome": "Hi!",
+            "announcement": null,
+            "budget": {"daily_usd": 5.0, "prices": {
+                "gpt-3.5-turbo": {"prompt": 0.0015, "completion": 0.002},
+                "gpt-4": {"prompt": 0.03, "completion": 0.06},
+                "gpt-4-32k": {"prompt": 0.06, "completion": 0.12},
+                "claude-3-haiku-20240307": {"prompt": 0.00025, "completion": 0.00125}
+            }}
+        }"#;
+        let config: Config = serde_json::from_str(json).unwrap();
+        let hash = config.hash();
+        for _ in 0..20 {
+            assert_eq!(config.clone().hash(), hash);
+            assert_eq!(serde_json::from_str::<Config>(json).unwrap().hash(), hash);
+        }
+    }
+
+    #[test]
+    fn validate_knowledge() {
+        let mut config = valid_config();
+        config.knowledge = Some(KnowledgeConfig::default());
+        config.provider = ProviderConfig::Anthropic;
+        let fields: Vec<String> = config.validate().into_iter().map(|e| e.field).collect();
+        assert_eq!(fields, vec!["knowledge.embedding_model", "knowledge"]);
+
+        config.provider = ProviderConfig::default();
+        config.prompt.messages[0].content = "Answer from:\n\n[KNOWLEDGE]".to_string();
+        assert_eq!(config.validate(), vec![]);
+    }
+
+    #[test]
+    fn validate_tools() {
+        let mut config = valid_config();
+        config.tools = Some(serde_json::from_str(
+            r#"{"blog_feed_url": "ftp://tomshen.io/feed.json", "faq": [{"question": "Who?", "answer": ""}], "max_iterations": 0}"#,
+        ).unwrap());
+        config.fallbacks = vec![Target {
+            provider: ProviderConfig::Anthropic,
+            model: "claude-3-haiku-20240307".to_string(),
+        }];
+        let fields: Vec<String> = config.validate().into_iter().map(|e| e.field).collect();
+        assert_eq!(
+            fields,
+            vec![
+                "fallbacks[0].provider",
+                "tools.max_iterations",
+                "tools.blog_feed_url",
+                "tools.faq[0]",
+            ]
+        );
+
+        config.fallbacks.clear();
+        config.tools = Some(ToolsConfig::default());
+        assert_eq!(config.validate(), vec![]);
+    }
+
+    #[test]
+    fn validate_experiment() {
+        let mut config = valid_config();
+        config.experiment = Some(serde_json::from_str(
+            r#"{"seed": "", "variants": [
+                {"name": "control", "weight": 0},
+                {"name": "Terse", "weight": 0, "max_tokens": 0},
+                {"name": "control", "weight": 0, "messages": [{"role": "user", "content": "[OWNER]"}]}
+            ]}"#,
+        ).unwrap());
+        let fields: Vec<String> = config.validate().into_iter().map(|e| e.field).collect();
+        assert_eq!(
+            fields,
+            vec![
+                "experiment.seed",
+                "experiment.variants",
+                "experiment.variants[1].name",
+                "experiment.variants[1].max_tokens",
+                "experiment.variants[2].name",
+                "experiment.variants[2].messages[0]",
+                "experiment.variants[2].messages[0].content",
+            ]
+        );
+
+        config.experiment = Some(
+            serde_json::from_str(
+                r#"{"seed": "2023-05", "variants": [
+                {"name": "control", "weight": 1},
+                {"name": "gpt-4", "weight": 1, "model": "gpt-4"}
+            ]}"#,
+            )
+            .unwrap(),
+        );
+        assert_eq!(config.validate(), vec![]);
+    }
+
+    #[test]
+    fn validate_variables() {
+        let mut config = valid_config();
+        config.prompt.messages[0].content =
+            r#"[IF COUNTRY == "CN"][GREETING][END] [OWNER] [IF CITY]"#.to_string();
+        config
+            .variables
+            .insert("GREETING".to_string(), "你好".to_string());
+        config
+            .variables
+            .insert("CITY".to_string(), "Seattle".to_string());
+        config
+            .variables
+            .insert("owner".to_string(), "Tom".to_string());
+        let messages: Vec<String> = config.validate().into_iter().map(|e| e.message).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "[IF] without [END]",
+                "built-in variables can't be overridden",
+                "names must be UPPER_SNAKE_CASE",
+            ]
+        );
+
+        config.prompt.messages[0].content =
+            r#"[IF COUNTRY == "CN"][GREETING][END] [OWNER]"#.to_string();
+        let messages: Vec<String> = config.validate().into_iter().map(|e| e.message).collect();
+        assert_eq!(messages[0], "unknown placeholder [OWNER]");
+    }
+
+    #[test]
+    fn render_all_messages() {
+        let mut config = valid_config();
+        config.site_name = Some("tomshen.io".to_string());
+        config.prompt.messages = vec![
+            Message {
+                role: Role::System,
+                content: "[SITE_NAME], [CURRENT_DATE]. Try:\n[QUESTIONS]".to_string(),
+            },
+            Message {
+                role: Role::Assistant,
+                content: r#"[IF COUNTRY == "CN"]你好[ELSE]Hello[END] from [CITY]"#.to_string(),
+            },
+        ];
+        let mut variables = config.template_variables("CN", "Shanghai");
+        variables.insert("CURRENT_DATE".to_string(), "2023-05-01".to_string());
+
+        let history = [Message {
+            role: Role::User,
+            content: "[CITY] is not rendered in history".to_string(),
+        }];
+        let request = RequestToOpenAI::new(
+            config.prompt,
+            &history,
+            "[SITE_NAME]?".to_string(),
+            &variables,
+        )
+        .unwrap();
+        let contents: Vec<&str> = request
+            .messages
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                "tomshen.io, 2023-05-01. Try:\n- a\n- b\n- c",
+                "你好 from Shanghai",
+                "[CITY] is not rendered in history",
+                "[SITE_NAME]?",
+            ]
+        );
+    }
 }
@@ -0,0 +1,529 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::prompt::{RequestToOpenAI, Role};
+use crate::stream_parser::{FinishReason, StreamItem};
+use crate::tools::ToolCallDelta;
+use crate::usage::TokenUsage;
+
+/// An upstream chat completion API.
+pub trait Provider {
+    /// URL the chat completion request is posted to
+    fn endpoint(&self) -> String;
+
+    /// Headers to attach to the chat completion request, including credentials.
+    fn headers(&self, api_key: Option<&str>) -> Vec<(&'static str, String)>;
+
+    /// Body of the chat completion request, with streaming enabled.
+    fn request_body(&self, request: &RequestToOpenAI) -> serde_json::Value;
+
+    /// Decode the `data` field of one server-sent event. Events that carry nothing the client
+    /// needs to know about decode to nothing.
+    fn decode_event(&self, data: &str) -> Vec<StreamItem>;
+
+    /// URL OpenAI-style embedding requests for `model` are posted to, `None` if the provider
+    /// can't embed text.
+    fn embeddings_endpoint(&self, _model: &str) -> Option<String> {
+        None
+    }
+
+    /// Whether `request_body` passes on the tools of a request, and `decode_event` the calls.
+    fn supports_tools(&self) -> bool {
+        false
+    }
+}
+
+/// Which provider to use, as stored in the KV `config`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(tag = "kind")]
+pub enum ProviderConfig {
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAI,
+    #[serde(rename = "azure")]
+    Azure {
+        /// e.g. `https://my-resource.openai.azure.com`
+        endpoint: String,
+        deployment: String,
+        api_version: String,
+    },
+    #[serde(rename = "anthropic")]
+    Anthropic,
+    /// Ollama, vLLM, or anything else exposing `/chat/completions` the way OpenAI does.
+    #[serde(rename = "openai_compatible")]
+    OpenAICompatible {
+        /// e.g. `http://localhost:11434/v1`
+        base_url: String,
+        /// env var holding the api key, if the server needs one
+        api_key_var: Option<String>,
+    },
+}
+
+impl ProviderConfig {
+    /// Name of the env var holding the api key of this provider.
+    pub fn api_key_var(&self) -> Option<&str> {
+        match self {
+            ProviderConfig::OpenAI => Some("OPENAI_API_KEY"),
+            ProviderConfig::Azure { .. } => Some("AZURE_OPENAI_API_KEY"),
+            ProviderConfig::Anthropic => Some("ANTHROPIC_API_KEY"),
+            ProviderConfig::OpenAICompatible { api_key_var, .. } => api_key_var.as_deref(),
+        }
+    }
+
+    /// Short name used in logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProviderConfig::OpenAI => "openai",
+            ProviderConfig::Azure { .. } => "azure",
+            ProviderConfig::Anthropic => "anthropic",
+            ProviderConfig::OpenAICompatible { .. } => "openai_compatible",
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn Provider> {
+        match self {
+            ProviderConfig::OpenAI => Box::new(OpenAI),
+            ProviderConfig::Azure {
+                endpoint,
+                deployment,
+                api_version,
+            } => Box::new(Azure {
+                endpoint: endpoint.clone(),
+                deployment: deployment.clone(),
+                api_version: api_version.clone(),
+            }),
+            ProviderConfig::Anthropic => Box::new(Anthropic),
+            ProviderConfig::OpenAICompatible { base_url, .. } => Box::new(OpenAICompatible {
+                base_url: base_url.clone(),
+            }),
+        }
+    }
+}
+
+/// A model served by a provider. Fallback targets are tried in order when the previous one fails.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub provider: ProviderConfig,
+    pub model: String,
+}
+
+/// One try at getting an answer from a target, recorded in the chat log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Attempt {
+    pub provider: String,
+    pub model: String,
+    /// HTTP status of the upstream response, `None` if it never came
+    pub status: Option<u16>,
+    /// whether we gave up waiting for the first delta
+    pub timed_out: bool,
+}
+
+/// Decode a chunk in OpenAI's format, which carries a choice, token usage, or both.
+fn decode_openai_chunk(data: &str) -> Vec<StreamItem> {
+    let chunk = match serde_json::from_str::<serde_json::Value>(data) {
+        Ok(chunk) => chunk,
+        // `[DONE]` is handled by the parser, so this is a malformed chunk
+        Err(_) => return Vec::new(),
+    };
+    let mut items: Vec<StreamItem> = StreamItem::from_json_value(&chunk).into_iter().collect();
+    if let Some(delta) = chunk.pointer("/choices/0/delta") {
+        items.extend(
+            ToolCallDelta::from_delta(delta)
+                .into_iter()
+                .map(StreamItem::ToolCallDelta),
+        );
+    }
+    if let Some(usage) = chunk.get("usage").filter(|usage| !usage.is_null()) {
+        if let Ok(usage) = serde_json::from_value::<TokenUsage>(usage.clone()) {
+            items.push(StreamItem::Usage(usage));
+        }
+    }
+    items
+}
+
+/// A request in OpenAI's format, with the messages of any tool calls after the question.
+fn openai_body(request: &RequestToOpenAI) -> serde_json::Value {
+    let mut body = serde_json::to_value(request).unwrap();
+    if let Some(messages) = body["messages"].as_array_mut() {
+        messages.extend(request.tool_messages.iter().cloned());
+    }
+    body
+}
+
+fn bearer_headers(api_key: Option<&str>) -> Vec<(&'static str, String)> {
+    let mut headers = vec![("Content-Type", "application/json".to_string())];
+    if let Some(api_key) = api_key {
+        headers.push(("Authorization", format!("Bearer {}", api_key)));
+    }
+    headers
+}
+
+pub struct OpenAI;
+
+impl Provider for OpenAI {
+    fn endpoint(&self) -> String {
+        "https://api.openai.com/v1/chat/completions".to_string()
+    }
+
+    fn embeddings_endpoint(&self, _model: &str) -> Option<String> {
+        Some("https://api.openai.com/v1/embeddings".to_string())
+    }
+
+    fn headers(&self, api_key: Option<&str>) -> Vec<(&'static str, String)> {
+        bearer_headers(api_key)
+    }
+
+    fn request_body(&self, request: &RequestToOpenAI) -> serde_json::Value {
+        let mut body = openai_body(request);
+        // ask for a final chunk with the token usage of the request
+        body["stream_options"] = json!({ "include_usage": true });
+        body
+    }
+
+    fn decode_event(&self, data: &str) -> Vec<StreamItem> {
+        decode_openai_chunk(data)
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+}
+
+pub struct Azure {
+    endpoint: String,
+    deployment: String,
+    api_version: String,
+}
+
+impl Provider for Azure {
+    fn endpoint(&self) -> String {
+        format!(
+            "{}/openai/deployments/{}/chat/completions?api-version={}",
+            self.endpoint.trim_end_matches('/'),
+            self.deployment,
+            self.api_version
+        )
+    }
+
+    /// Embeddings come from their own deployment, named by the model.
+    fn embeddings_endpoint(&self, model: &str) -> Option<String> {
+        Some(format!(
+            "{}/openai/deployments/{}/embeddings?api-version={}",
+            self.endpoint.trim_end_matches('/'),
+            model,
+            self.api_version
+        ))
+    }
+
+    fn headers(&self, api_key: Option<&str>) -> Vec<(&'static str, String)> {
+        let mut headers = vec![("Content-Type", "application/json".to_string())];
+        if let Some(api_key) = api_key {
+            headers.push(("api-key", api_key.to_string()));
+        }
+        headers
+    }
+
+    fn request_body(&self, request: &RequestToOpenAI) -> serde_json::Value {
+        // the deployment decides the model, the field is ignored
+        openai_body(request)
+    }
+
+    fn decode_event(&self, data: &str) -> Vec<StreamItem> {
+        // Azure sends an extra leading chunk with empty `choices` and content filter results,
+        // which decodes to nothing.
+        decode_openai_chunk(data)
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+}
+
+pub struct Anthropic;
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+impl Provider for Anthropic {
+    fn endpoint(&self) -> String {
+        "https://api.anthropic.com/v1/messages".to_string()
+    }
+
+    fn headers(&self, api_key: Option<&str>) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            ("Content-Type", "application/json".to_string()),
+            ("anthropic-version", ANTHROPIC_VERSION.to_string()),
+        ];
+        if let Some(api_key) = api_key {
+            headers.push(("x-api-key", api_key.to_string()));
+        }
+        headers
+    }
+
+    fn request_body(&self, request: &RequestToOpenAI) -> serde_json::Value {
+        // the Messages API takes the system prompt as a separate field
+        let system = request
+            .messages
+            .iter()
+            .filter(|m| m.role == Role::System)
+            .map(|m| m.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let messages = request
+            .messages
+            .iter()
+            .filter(|m| m.role != Role::System)
+            .collect::<Vec<_>>();
+        json!({
+            "model": request.model,
+            "system": system,
+            "messages": messages,
+            "max_tokens": request.max_tokens,
+            "stream": true,
+        })
+    }
+
+    fn decode_event(&self, data: &str) -> Vec<StreamItem> {
+        decode_anthropic_event(data).unwrap_or_default()
+    }
+}
+
+fn decode_anthropic_event(data: &str) -> Option<Vec<StreamItem>> {
+    let event = serde_json::from_str::<serde_json::Value>(data).ok()?;
+    let tokens_of = |usage: &serde_json::Value, field: &str| {
+        usage.get(field).and_then(|t| t.as_u64()).unwrap_or(0) as u32
+    };
+    match event.get("type")?.as_str()? {
+        "message_start" => {
+            let usage = event.get("message")?.get("usage")?;
+            let usage = TokenUsage {
+                prompt_tokens: tokens_of(usage, "input_tokens"),
+                // the final count comes with `message_delta`
+                completion_tokens: 0,
+                estimated: false,
+            };
+            Some(vec![StreamItem::RoleMsg, StreamItem::Usage(usage)])
+        }
+        "content_block_delta" => {
+            let text = event.get("delta")?.get("text")?.as_str()?;
+            Some(vec![StreamItem::Delta(text.to_string())])
+        }
+        "message_delta" => {
+            let stop_reason = event.get("delta")?.get("stop_reason")?.as_str()?;
+            let finish_reason = match stop_reason {
+                "max_tokens" => FinishReason::Length,
+                _ => FinishReason::Stop,
+            };
+            let mut items = vec![StreamItem::Finish(finish_reason)];
+            if let Some(usage) = event.get("usage") {
+                items.push(StreamItem::Usage(TokenUsage {
+                    prompt_tokens: 0,
+                    completion_tokens: tokens_of(usage, "output_tokens"),
+                    estimated: false,
+                }));
+            }
+            Some(items)
+        }
+        "error" => {
+            let message = event.get("error")?.get("message")?.as_str()?;
+            Some(vec![StreamItem::Error(message.to_string())])
+        }
+        // ping, content_block_start, content_block_stop, message_stop
+        _ => None,
+    }
+}
+
+pub struct OpenAICompatible {
+    base_url: String,
+}
+
+impl Provider for OpenAICompatible {
+    fn endpoint(&self) -> String {
+        format!("{}/chat/completions", self.base_url.trim_end_matches('/'))
+    }
+
+    fn embeddings_endpoint(&self, _model: &str) -> Option<String> {
+        Some(format!(
+            "{}/embeddings",
+            self.base_url.trim_end_matches('/')
+        ))
+    }
+
+    fn headers(&self, api_key: Option<&str>) -> Vec<(&'static str, String)> {
+        bearer_headers(api_key)
+    }
+
+    fn request_body(&self, request: &RequestToOpenAI) -> serde_json::Value {
+        openai_body(request)
+    }
+
+    fn decode_event(&self, data: &str) -> Vec<StreamItem> {
+        decode_openai_chunk(data)
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prompt::Message;
+    use crate::stream_parser::ChatStreamParser;
+
+    fn decode_fixture(provider: Box<dyn Provider>, fixture: &str) -> Vec<StreamItem> {
+        let mut parser = ChatStreamParser::new(provider);
+        parser.add_chunk(fixture.as_bytes());
+        let mut items = Vec::new();
+        while let Some(item) = parser.next() {
+            items.push(item);
+        }
+        items
+    }
+
+    fn finish_of(items: &[StreamItem]) -> Option<&FinishReason> {
+        items.iter().find_map(|item| match item {
+            StreamItem::Finish(reason) => Some(reason),
+            _ => None,
+        })
+    }
+
+    fn usage_of(items: &[StreamItem]) -> TokenUsage {
+        items
+            .iter()
+            .filter_map(|item| match item {
+                StreamItem::Usage(usage) => Some(*usage),
+                _ => None,
+            })
+            .fold(TokenUsage::default(), |total, usage| total + usage)
+    }
+
+    fn answer_of(items: &[StreamItem]) -> String {
+        items
+            .iter()
+            .filter_map(|item| match item {
+                StreamItem::Delta(delta) => Some(delta.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn request() -> RequestToOpenAI {
+        RequestToOpenAI {
+            model: "gpt-3.5-turbo".to_string(),
+            messages: vec![
+                Message {
+                    role: Role::System,
+                    content: "You are a helpful chatbot.".to_string(),
+                },
+                Message {
+                    role: Role::User,
+                    content: "Hello!".to_string(),
+                },
+            ],
+            max_tokens: 64,
+            stream: true,
+            tools: Vec::new(),
+            tool_choice: None,
+            tool_messages: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn openai_stream() {
+        let items = decode_fixture(
+            ProviderConfig::OpenAI.build(),
+            include_str!("fixtures/openai.sse"),
+        );
+        assert_eq!(items.first(), Some(&StreamItem::RoleMsg));
+        assert_eq!(answer_of(&items), "Hello! How can I help you today?");
+        assert_eq!(finish_of(&items), Some(&FinishReason::Stop));
+        assert_eq!(usage_of(&items).prompt_tokens, 19);
+        assert_eq!(usage_of(&items).completion_tokens, 10);
+    }
+
+    #[test]
+    fn azure_stream() {
+        let provider = ProviderConfig::Azure {
+            endpoint: "https://salieri.openai.azure.com/".to_string(),
+            deployment: "gpt35".to_string(),
+            api_version: "2023-05-15".to_string(),
+        }
+        .build();
+        assert_eq!(
+            provider.endpoint(),
+            "https://salieri.openai.azure.com/openai/deployments/gpt35/chat/completions?api-version=2023-05-15"
+        );
+        assert!(provider
+            .headers(Some("key"))
+            .contains(&("api-key", "key".to_string())));
+
+        let items = decode_fixture(provider, include_str!("fixtures/azure.sse"));
+        assert_eq!(answer_of(&items), "Hi there!");
+        assert_eq!(finish_of(&items), Some(&FinishReason::Stop));
+    }
+
+    #[test]
+    fn anthropic_stream() {
+        let items = decode_fixture(
+            ProviderConfig::Anthropic.build(),
+            include_str!("fixtures/anthropic.sse"),
+        );
+        assert_eq!(items.first(), Some(&StreamItem::RoleMsg));
+        assert_eq!(answer_of(&items), "Hello! I'm Salieri.");
+        assert_eq!(finish_of(&items), Some(&FinishReason::Length));
+        assert_eq!(usage_of(&items).prompt_tokens, 25);
+        assert_eq!(usage_of(&items).completion_tokens, 8);
+    }
+
+    #[test]
+    fn openai_request_body() {
+        let body = ProviderConfig::OpenAI.build().request_body(&request());
+        assert_eq!(body["model"], "gpt-3.5-turbo");
+        assert_eq!(body["stream_options"]["include_usage"], true);
+    }
+
+    #[test]
+    fn anthropic_request_body() {
+        let body = ProviderConfig::Anthropic.build().request_body(&request());
+        assert_eq!(body["system"], "You are a helpful chatbot.");
+        assert_eq!(body["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(body["messages"][0]["role"], "user");
+        assert_eq!(body["max_tokens"], 64);
+        assert_eq!(body["stream"], true);
+    }
+
+    #[test]
+    fn ollama_stream() {
+        let provider = ProviderConfig::OpenAICompatible {
+            base_url: "http://localhost:11434/v1/".to_string(),
+            api_key_var: None,
+        }
+        .build();
+        assert_eq!(
+            provider.endpoint(),
+            "http://localhost:11434/v1/chat/completions"
+        );
+        assert!(provider
+            .headers(None)
+            .iter()
+            .all(|(name, _)| *name != "Authorization"));
+
+        let items = decode_fixture(provider, include_str!("fixtures/ollama.sse"));
+        assert_eq!(answer_of(&items), "Hello there.");
+        assert_eq!(finish_of(&items), Some(&FinishReason::Stop));
+    }
+
+    #[test]
+    fn provider_config_from_json() {
+        let config: ProviderConfig = serde_json::from_str(
+            r#"{"kind": "openai_compatible", "base_url": "http://localhost:11434/v1"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.api_key_var(), None);
+
+        let config: ProviderConfig = serde_json::from_str(r#"{"kind": "anthropic"}"#).unwrap();
+        assert_eq!(config, ProviderConfig::Anthropic);
+        assert_eq!(config.api_key_var(), Some("ANTHROPIC_API_KEY"));
+    }
+}
@@ -0,0 +1,154 @@
+use serde::{Deserialize, Serialize};
+use worker::kv::KvStore;
+
+use crate::error::{Error, Result};
+use crate::id;
+use crate::usage;
+
+const MINUTE: i64 = 60;
+const DAY: i64 = 60 * 60 * 24;
+
+/// Limits on how many questions can be asked. Every limit is optional.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct RateLimitConfig {
+    /// questions per IP in any 60 second window
+    pub per_ip_per_minute: Option<u32>,
+    /// questions per IP in any 24 hour window
+    pub per_ip_per_day: Option<u32>,
+    /// questions across all visitors per UTC day
+    pub global_requests_per_day: Option<u64>,
+    /// completion tokens across all visitors per UTC day
+    pub global_tokens_per_day: Option<u64>,
+}
+
+/// Seconds until one more request fits into a sliding window of `window` seconds that allows
+/// `limit` requests, or `None` if it fits now. `timestamps` must be sorted.
+fn retry_after(timestamps: &[i64], now: i64, window: i64, limit: u32) -> Option<u64> {
+    let in_window = &timestamps[timestamps.partition_point(|t| *t <= now - window)..];
+    let limit = limit as usize;
+    if in_window.len() < limit {
+        None
+    } else if limit == 0 {
+        Some(window as u64)
+    } else {
+        // wait until enough of the oldest requests leave the window
+        let oldest_kept = in_window[in_window.len() - limit];
+        Some((oldest_kept + window - now).max(1) as u64)
+    }
+}
+
+/// Sliding window limiter keyed by IP, plus a global daily ceiling. Counters live in KV.
+pub struct RateLimiter {
+    kv: KvStore,
+    config: RateLimitConfig,
+}
+
+impl RateLimiter {
+    pub fn new(kv: KvStore, config: RateLimitConfig) -> Self {
+        Self { kv, config }
+    }
+
+    fn history_key(key: &str) -> String {
+        format!("ratelimit_{}", key)
+    }
+
+    async fn history(&self, key: &str) -> Result<Vec<i64>> {
+        Ok(self
+            .kv
+            .get(&Self::history_key(key))
+            .json::<Vec<i64>>()
+            .await?
+            .unwrap_or_default())
+    }
+
+    fn check_history(&self, history: &[i64], now: i64) -> Result<()> {
+        let limits = [
+            (MINUTE, self.config.per_ip_per_minute),
+            (DAY, self.config.per_ip_per_day),
+        ];
+        for &(window, limit) in limits.iter() {
+            if let Some(limit) = limit {
+                if let Some(seconds) = retry_after(history, now, window, limit) {
+                    return Err(Error::TooManyRequests(seconds));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn check_global(&self, now: i64) -> Result<()> {
+        if self.config.global_requests_per_day.is_none()
+            && self.config.global_tokens_per_day.is_none()
+        {
+            return Ok(());
+        }
+        let usage = usage::get_daily_usage(&self.kv, &id::get_utc_date()).await?;
+        let over_requests = self
+            .config
+            .global_requests_per_day
+            .is_some_and(|limit| usage.requests >= limit);
+        let over_tokens = self
+            .config
+            .global_tokens_per_day
+            .is_some_and(|limit| usage.completion_tokens >= limit);
+        if over_requests || over_tokens {
+            // the daily counters reset at UTC midnight
+            return Err(Error::TooManyRequests((DAY - now % DAY) as u64));
+        }
+        Ok(())
+    }
+
+    /// Fail with `Error::TooManyRequests` if `key` (usually the client IP) may not ask now.
+    pub async fn check(&self, key: &str) -> Result<()> {
+        let now = id::get_utc_timestamp_sec();
+        self.check_history(&self.history(key).await?, now)?;
+        self.check_global(now).await
+    }
+
+    /// Like `check`, but also count this request against `key` if it is allowed.
+    pub async fn acquire(&self, key: &str) -> Result<()> {
+        if self.config.per_ip_per_minute.is_none() && self.config.per_ip_per_day.is_none() {
+            return self.check_global(id::get_utc_timestamp_sec()).await;
+        }
+        let now = id::get_utc_timestamp_sec();
+        let mut history = self.history(key).await?;
+        self.check_history(&history, now)?;
+        self.check_global(now).await?;
+
+        history.retain(|t| *t > now - DAY);
+        history.push(now);
+        self.kv
+            .put(&Self::history_key(key), &history)?
+            .expiration_ttl(DAY as u64)
+            .execute()
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_window() {
+        let now = 1_000_000;
+        // nothing asked yet
+        assert_eq!(retry_after(&[], now, MINUTE, 2), None);
+        // one request left in the window
+        assert_eq!(retry_after(&[now - 30], now, MINUTE, 2), None);
+        // full: wait for the oldest request to leave the window
+        assert_eq!(retry_after(&[now - 30, now - 10], now, MINUTE, 2), Some(30));
+        // requests older than the window don't count
+        assert_eq!(
+            retry_after(&[now - 120, now - 60, now - 10], now, MINUTE, 2),
+            None
+        );
+        // over the limit: wait for enough requests to leave the window
+        assert_eq!(
+            retry_after(&[now - 50, now - 40, now - 10], now, MINUTE, 2),
+            Some(20)
+        );
+        assert_eq!(retry_after(&[], now, DAY, 0), Some(DAY as u64));
+    }
+}
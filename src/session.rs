@@ -0,0 +1,224 @@
+//! Session tokens, issued after a solved captcha so that later chats don't need another one.
+//!
+//! A token is `<payload>.<signature>`, both in hex: the payload is the JSON of
+//! [`SessionClaims`] and the signature its HMAC-SHA256 under the `SESSION_SECRET` env var.
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use worker::{console_log, kv::KvStore, Request, Response, RouteContext};
+
+use crate::admin::{query_param, verify_identity, verify_read_access};
+use crate::attach_origin_to_header;
+use crate::constants::KV_BINDING;
+use crate::error::{Error, Result};
+use crate::id;
+
+/// questions asked with a session are counted under this prefix followed by its id
+const QUESTIONS_PREFIX: &str = "session_questions_";
+/// revoked sessions are listed under this prefix followed by their id
+const REVOKED_PREFIX: &str = "session_revoked_";
+/// longest allowed `ttl_minutes`, which is also how long revocations are kept
+pub const MAX_TTL_MINUTES: u32 = 60 * 24;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// how long a token is valid after the captcha was solved
+    pub ttl_minutes: u32,
+    /// questions a token is good for, including the one asked with the captcha
+    pub max_questions: u32,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            ttl_minutes: 30,
+            max_questions: 20,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionClaims {
+    pub id: String,
+    /// hash of the IP the captcha was solved from
+    ip: String,
+    /// seconds since epoch
+    pub expires_at: i64,
+    pub max_questions: u32,
+}
+
+fn hash_ip(remote_ip: &str) -> String {
+    hex::encode(&Sha256::digest(remote_ip.as_bytes())[..8])
+}
+
+fn mac(secret: &str, payload: &[u8]) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(payload);
+    mac
+}
+
+/// Issue a token for a visitor who just solved a captcha from `remote_ip`.
+pub fn issue(
+    config: &SessionConfig,
+    secret: &str,
+    remote_ip: &str,
+    now: i64,
+) -> (String, SessionClaims) {
+    let claims = SessionClaims {
+        id: hex::encode(rand::random::<[u8; 16]>()),
+        ip: hash_ip(remote_ip),
+        expires_at: now + config.ttl_minutes as i64 * 60,
+        max_questions: config.max_questions,
+    };
+    let payload = serde_json::to_vec(&claims).expect("claims serialize");
+    let signature = mac(secret, &payload).finalize().into_bytes();
+    let token = format!("{}.{}", hex::encode(&payload), hex::encode(signature));
+    (token, claims)
+}
+
+/// Check the signature, IP and expiry of a token. Revocation and the question count are
+/// checked by [`use_question`].
+pub fn decode(token: &str, secret: &str, remote_ip: &str, now: i64) -> Result<SessionClaims> {
+    let invalid = || Error::InvalidRequest("session token is invalid".to_string());
+    let (payload, signature) = token.split_once('.').ok_or_else(invalid)?;
+    let payload = hex::decode(payload).map_err(|_| invalid())?;
+    let signature = hex::decode(signature).map_err(|_| invalid())?;
+    mac(secret, &payload)
+        .verify_slice(&signature)
+        .map_err(|_| invalid())?;
+
+    let claims: SessionClaims = serde_json::from_slice(&payload).map_err(|_| invalid())?;
+    if claims.ip != hash_ip(remote_ip) {
+        return Err(Error::InvalidRequest(
+            "session token was issued to another IP".to_string(),
+        ));
+    }
+    if now >= claims.expires_at {
+        return Err(Error::InvalidRequest("session token expired".to_string()));
+    }
+    Ok(claims)
+}
+
+/// Count one more question against a session, unless it is revoked or used up.
+pub async fn use_question(kv: &KvStore, claims: &SessionClaims, now: i64) -> Result<()> {
+    if kv
+        .get(&format!("{}{}", REVOKED_PREFIX, claims.id))
+        .text()
+        .await?
+        .is_some()
+    {
+        return Err(Error::InvalidRequest(
+            "session token was revoked".to_string(),
+        ));
+    }
+
+    let key = format!("{}{}", QUESTIONS_PREFIX, claims.id);
+    let questions = kv.get(&key).json::<u32>().await?.unwrap_or(0);
+    if questions >= claims.max_questions {
+        return Err(Error::InvalidRequest(
+            "session token is used up".to_string(),
+        ));
+    }
+    kv.put(&key, questions + 1)?
+        // KV doesn't take TTLs under a minute
+        .expiration_ttl((claims.expires_at - now).max(60) as u64)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Revoke the session `?id=`, the `session_id` of its chats in the logs.
+pub async fn handle_session_revoke(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    verify_identity(&req, &ctx.env).await?;
+
+    let id = query_param(&req, "id")?;
+    let kv = ctx.kv(KV_BINDING)?;
+    // no token outlives the longest TTL, so neither does its revocation
+    kv.put(
+        &format!("{}{}", REVOKED_PREFIX, id),
+        id::get_utc_timestamp_sec(),
+    )?
+    .expiration_ttl(MAX_TTL_MINUTES as u64 * 60)
+    .execute()
+    .await?;
+    console_log!("session {} revoked", id);
+
+    let mut resp = Response::from_json(&json!({ "success": true }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+/// Sessions revoked within the longest TTL, with when each revocation lapses.
+pub async fn handle_sessions_revoked(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    verify_read_access(&req, &ctx).await?;
+
+    let kv = ctx.kv(KV_BINDING)?;
+    let mut revoked = Vec::new();
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(REVOKED_PREFIX.to_string());
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        for key in page.keys {
+            revoked.push(json!({
+                "id": key.name.trim_start_matches(REVOKED_PREFIX),
+                "expiration": key.expiration,
+            }));
+        }
+        if page.list_complete {
+            break;
+        }
+        cursor = page.cursor;
+    }
+
+    let mut resp = Response::from_json(&json!({ "revoked": revoked }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "secret";
+    const IP: &str = "203.0.113.7";
+
+    #[test]
+    fn issue_and_decode() {
+        let config = SessionConfig::default();
+        let (token, claims) = issue(&config, SECRET, IP, 1_000);
+        assert_eq!(claims.expires_at, 1_000 + 30 * 60);
+        assert_eq!(claims.max_questions, 20);
+        assert_eq!(decode(&token, SECRET, IP, 1_000).unwrap(), claims);
+
+        let err = |result: Result<SessionClaims>| result.unwrap_err().to_string();
+        assert!(err(decode(&token, SECRET, IP, claims.expires_at)).contains("expired"));
+        assert!(err(decode(&token, SECRET, "203.0.113.8", 1_000)).contains("another IP"));
+        assert!(err(decode(&token, "other secret", IP, 1_000)).contains("invalid"));
+        assert!(err(decode("not a token", SECRET, IP, 1_000)).contains("invalid"));
+    }
+
+    #[test]
+    fn tampered_token() {
+        let (token, claims) = issue(&SessionConfig::default(), SECRET, IP, 1_000);
+        let forged = SessionClaims {
+            max_questions: 1_000,
+            ..claims
+        };
+        let signature = token.split_once('.').unwrap().1;
+        let forged = format!(
+            "{}.{}",
+            hex::encode(serde_json::to_vec(&forged).unwrap()),
+            signature
+        );
+        assert!(decode(&forged, SECRET, IP, 1_000).is_err());
+    }
+}
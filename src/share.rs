@@ -0,0 +1,188 @@
+//! Sharing of logged chats. A chat is private until the visitor who asked it shares it, which
+//! creates an unguessable slug for `/lookup`. Visitors prove a chat is theirs with the deletion
+//! secret sent in its end message.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use worker::{console_log, kv::KvStore, Request, Response, RouteContext};
+
+use crate::admin::{query_param, verify_identity};
+use crate::apikey::{self, Scope};
+use crate::constants::{KV_BINDING, KV_LOG_BINDING};
+use crate::error::{Error, Result};
+use crate::{attach_origin_to_header, id, LogKvEntry};
+
+/// shares are stored in the log KV under this prefix followed by the slug
+const SHARE_PREFIX: &str = "share_";
+
+/// A share slug, pointing at a log entry.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct Share {
+    id: String,
+    created_at: i64,
+    /// `None` if the share never expires
+    expires_at: Option<i64>,
+}
+
+fn share_key(slug: &str) -> String {
+    format!("{}{}", SHARE_PREFIX, slug)
+}
+
+/// A new deletion secret, and the hash of it to keep in the log entry.
+pub fn make_deletion_secret() -> (String, String) {
+    let secret = hex::encode(rand::random::<[u8; 16]>());
+    let hash = hash_secret(&secret);
+    (secret, hash)
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+fn not_found(id: &str) -> Error {
+    Error::NotFound(format!("No chat found with id {}", id))
+}
+
+/// Read the log entry `id`, if `deletion_secret` is the one it was answered with.
+pub(crate) async fn owned_entry(log_kv: &KvStore, id: &str, deletion_secret: &str) -> Result<LogKvEntry> {
+    let entry = log_kv
+        .get(id)
+        .json::<LogKvEntry>()
+        .await?
+        .ok_or_else(|| not_found(id))?;
+    if entry.deletion_secret_hash.as_deref() != Some(hash_secret(deletion_secret).as_str()) {
+        return Err(Error::Forbidden);
+    }
+    Ok(entry)
+}
+
+/// Delete the log entry `id` along with its share, if any.
+async fn delete_chat(log_kv: &KvStore, id: &str, entry: &LogKvEntry) -> Result<()> {
+    if let Some(slug) = &entry.share {
+        log_kv.delete(&share_key(slug)).await?;
+    }
+    log_kv.delete(id).await?;
+    Ok(())
+}
+
+/// The chat shared as `slug`. With a lookup API key, any chat can be read by its log id.
+pub async fn lookup(req: &Request, ctx: &RouteContext<()>, slug: &str) -> Result<LogKvEntry> {
+    let log_kv = ctx.kv(KV_LOG_BINDING)?;
+    let id = match log_kv.get(&share_key(slug)).json::<Share>().await? {
+        Some(share) => share.id,
+        None => match apikey::authenticate(req, &ctx.kv(KV_BINDING)?, Scope::Lookup).await? {
+            Some(_) => slug.to_string(),
+            None => return Err(not_found(slug)),
+        },
+    };
+    log_kv
+        .get(&id)
+        .json::<LogKvEntry>()
+        .await?
+        .ok_or_else(|| not_found(slug))
+}
+
+#[derive(Deserialize)]
+struct ShareRequest {
+    id: String,
+    deletion_secret: String,
+    /// never expires if omitted
+    ttl_days: Option<u32>,
+}
+
+/// Share a chat, replacing any earlier share of it.
+pub async fn handle_share(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let share_req: ShareRequest = serde_json::from_str(&req.text().await?)
+        .map_err(|e| Error::InvalidRequest(format!("malformed share request: {}", e)))?;
+    if share_req.ttl_days == Some(0) {
+        return Err(Error::InvalidRequest(
+            "ttl_days must be at least 1".to_string(),
+        ));
+    }
+
+    let log_kv = ctx.kv(KV_LOG_BINDING)?;
+    let mut entry = owned_entry(&log_kv, &share_req.id, &share_req.deletion_secret).await?;
+    if let Some(old_slug) = &entry.share {
+        log_kv.delete(&share_key(old_slug)).await?;
+    }
+
+    let slug = hex::encode(rand::random::<[u8; 16]>());
+    let now = id::get_utc_timestamp_sec();
+    let ttl = share_req.ttl_days.map(|days| days as u64 * 60 * 60 * 24);
+    let share = Share {
+        id: share_req.id.clone(),
+        created_at: now,
+        expires_at: ttl.map(|ttl| now + ttl as i64),
+    };
+    let mut put = log_kv.put(&share_key(&slug), &share)?;
+    if let Some(ttl) = ttl {
+        put = put.expiration_ttl(ttl);
+    }
+    put.execute().await?;
+    entry.share = Some(slug.clone());
+    log_kv.put(&share_req.id, &entry)?.execute().await?;
+
+    let mut resp = Response::from_json(&json!({
+        "slug": slug,
+        "expires_at": share.expires_at,
+    }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+#[derive(Deserialize)]
+struct DeleteRequest {
+    id: String,
+    deletion_secret: String,
+}
+
+/// Delete a chat on behalf of the visitor who asked it.
+pub async fn handle_delete(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let delete_req: DeleteRequest = serde_json::from_str(&req.text().await?)
+        .map_err(|e| Error::InvalidRequest(format!("malformed delete request: {}", e)))?;
+
+    let log_kv = ctx.kv(KV_LOG_BINDING)?;
+    let entry = owned_entry(&log_kv, &delete_req.id, &delete_req.deletion_secret).await?;
+    delete_chat(&log_kv, &delete_req.id, &entry).await?;
+
+    let mut resp = Response::from_json(&json!({ "success": true }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+/// Delete any chat, by its log id or share slug.
+pub async fn handle_takedown(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    verify_identity(&req, &ctx.env).await?;
+
+    let id = query_param(&req, "id")?;
+    let log_kv = ctx.kv(KV_LOG_BINDING)?;
+    let id = match log_kv.get(&share_key(&id)).json::<Share>().await? {
+        Some(share) => share.id,
+        None => id,
+    };
+    let entry = log_kv
+        .get(&id)
+        .json::<LogKvEntry>()
+        .await?
+        .ok_or_else(|| not_found(&id))?;
+    delete_chat(&log_kv, &id, &entry).await?;
+    console_log!("chat {} taken down", id);
+
+    let mut resp = Response::from_json(&json!({ "success": true, "id": id }))?;
+    attach_origin_to_header(&req, resp.headers_mut())?;
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deletion_secret() {
+        let (secret, hash) = make_deletion_secret();
+        assert_eq!(secret.len(), 32);
+        assert_eq!(hash, hash_secret(&secret));
+        assert_ne!(make_deletion_secret().0, secret);
+    }
+}
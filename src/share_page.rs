@@ -0,0 +1,265 @@
+//! Server-rendered page of a shared chat, so that links to it get a preview on social media.
+
+use chrono::Datelike;
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag, TagEnd};
+use worker::{Request, Response, RouteContext};
+
+use crate::error::{Error, Result};
+use crate::{read_config, share, LogKvEntry};
+
+/// longest page title, in characters
+const TITLE_CHARS: usize = 70;
+/// longest description in the preview, in characters
+const DESCRIPTION_CHARS: usize = 200;
+const DEFAULT_SITE_NAME: &str = "Salieri";
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Cut `s` to at most `max` characters, ending with an ellipsis if anything was cut.
+fn truncate(s: &str, max: usize) -> String {
+    if s.chars().count() <= max {
+        return s.to_string();
+    }
+    let cut: String = s.chars().take(max - 1).collect();
+    format!("{}…", cut.trim_end())
+}
+
+/// Whether a link target is safe to put in an `href`: relative, or http(s) or mailto.
+fn is_safe_url(url: &str) -> bool {
+    let url = url.trim().to_ascii_lowercase();
+    match url.find([':', '/', '?', '#']) {
+        Some(i) if url[i..].starts_with(':') => ["http", "https", "mailto"].contains(&&url[..i]),
+        _ => true,
+    }
+}
+
+/// Render the Markdown of an answer as HTML. Raw HTML in it is shown as text, and links to
+/// anything but the web are dropped.
+fn render_markdown(markdown: &str) -> String {
+    let parser = Parser::new_ext(
+        markdown,
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH,
+    )
+    .map(|event| match event {
+        Event::Html(raw) | Event::InlineHtml(raw) => Event::Text(raw),
+        Event::Start(Tag::Link {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) if !is_safe_url(&dest_url) => Event::Start(Tag::Link {
+            link_type,
+            dest_url: CowStr::Borrowed("#"),
+            title,
+            id,
+        }),
+        Event::Start(Tag::Image {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) if !is_safe_url(&dest_url) => Event::Start(Tag::Image {
+            link_type,
+            dest_url: CowStr::Borrowed("#"),
+            title,
+            id,
+        }),
+        event => event,
+    });
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, parser);
+    rendered
+}
+
+/// Text of an answer without its Markdown, on one line.
+fn plain_text(markdown: &str) -> String {
+    let mut text = String::new();
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Text(t) | Event::Code(t) => text.push_str(&t),
+            Event::SoftBreak
+            | Event::HardBreak
+            | Event::End(
+                TagEnd::Paragraph
+                | TagEnd::Heading(_)
+                | TagEnd::Item
+                | TagEnd::CodeBlock
+                | TagEnd::TableCell,
+            ) => text.push(' '),
+            _ => {}
+        }
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn page(title: &str, head: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+{head}<style>
+body {{ margin: 0 auto; max-width: 42rem; padding: 2rem 1rem; font-family: system-ui, sans-serif; line-height: 1.6; color: #222; }}
+h1 {{ font-size: 1.4rem; }}
+pre {{ overflow-x: auto; padding: 0.75rem; background: #f4f4f4; }}
+footer {{ margin-top: 2rem; color: #777; font-size: 0.9rem; }}
+</style>
+</head>
+<body>
+{body}
+</body>
+</html>
+"#,
+        title = title,
+        head = head,
+        body = body,
+    )
+}
+
+fn render_share_page(entry: &LogKvEntry, site_name: &str, url: &str) -> String {
+    let title = escape(&truncate(&entry.question, TITLE_CHARS));
+    let description = escape(&truncate(&plain_text(&entry.response), DESCRIPTION_CHARS));
+    let site_name = escape(site_name);
+    let date = chrono::DateTime::from_timestamp(entry.timestamp, 0)
+        .map(|time| format!("{:04}-{:02}-{:02}", time.year(), time.month(), time.day()))
+        .unwrap_or_default();
+
+    let head = format!(
+        r#"<meta name="description" content="{description}">
+<meta property="og:type" content="article">
+<meta property="og:site_name" content="{site_name}">
+<meta property="og:title" content="{title}">
+<meta property="og:description" content="{description}">
+<meta property="og:url" content="{url}">
+<meta name="twitter:card" content="summary">
+<meta name="twitter:title" content="{title}">
+<meta name="twitter:description" content="{description}">
+"#,
+        description = description,
+        site_name = site_name,
+        title = title,
+        url = escape(url),
+    );
+    let body = format!(
+        r#"<main>
+<h1>{question}</h1>
+<article>
+{answer}</article>
+<footer><time datetime="{date}">{date}</time> · {site_name}</footer>
+</main>"#,
+        question = escape(&entry.question),
+        answer = render_markdown(&entry.response),
+        date = date,
+        site_name = site_name,
+    );
+    page(&title, &head, &body)
+}
+
+fn render_error_page(error: &Error) -> String {
+    let message = match error {
+        Error::NotFound(_) => "This chat doesn't exist, or is no longer shared.".to_string(),
+        error => error.to_string(),
+    };
+    page(
+        &error.status_code().to_string(),
+        "",
+        &format!("<main>\n<p>{}</p>\n</main>", escape(&message)),
+    )
+}
+
+/// `GET /api/salieri/share/:id`, which looks chats up like `/lookup` does. Errors are pages too,
+/// with the status they would have in JSON.
+pub async fn handle_share_page(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let result: Result<String> = async {
+        let slug = ctx
+            .param("id")
+            .ok_or_else(|| Error::InvalidRequest("Expected a share id".to_string()))?;
+        let entry = share::lookup(&req, &ctx, slug).await?;
+        let site_name = read_config(&ctx)
+            .await?
+            .site_name
+            .unwrap_or_else(|| DEFAULT_SITE_NAME.to_string());
+        Ok(render_share_page(&entry, &site_name, req.url()?.as_str()))
+    }
+    .await;
+
+    match result {
+        Ok(html) => Ok(Response::from_html(html)?),
+        Err(error) => {
+            let status = error.status_code();
+            Ok(Response::from_html(render_error_page(&error))?.with_status(status))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(question: &str, response: &str) -> LogKvEntry {
+        LogKvEntry::new(
+            question.to_string(),
+            response.to_string(),
+            "203.0.113.7".to_string(),
+            "SEA".to_string(),
+            1_700_000_000,
+            "conversation".to_string(),
+            0,
+        )
+    }
+
+    #[test]
+    fn share_page() {
+        let page = render_share_page(
+            &entry(
+                "Who is <Tom> & \"why\"?",
+                "**Tom** is a [dev](https://tomshen.io).\n\n<script>alert(1)</script>\n\n[x](javascript:alert(1))",
+            ),
+            "Tom's site",
+            "https://example.com/api/salieri/share/abc",
+        );
+        assert!(page.contains(
+            r#"<meta property="og:title" content="Who is &lt;Tom&gt; &amp; &quot;why&quot;?">"#
+        ));
+        assert!(page.contains(r#"<meta name="twitter:description" content="Tom is a dev. x">"#));
+        assert!(page.contains(r#"<meta property="og:site_name" content="Tom&#39;s site">"#));
+        assert!(page.contains(r#"<h1>Who is &lt;Tom&gt; &amp; &quot;why&quot;?</h1>"#));
+        assert!(page.contains(r#"<strong>Tom</strong> is a <a href="https://tomshen.io">dev</a>."#));
+        assert!(page.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(!page.contains("<script>"));
+        assert!(!page.contains("javascript:"));
+        assert!(page.contains(r#"<time datetime="2023-11-14">"#));
+    }
+
+    #[test]
+    fn helpers() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("你好世界你好世界", 5), "你好世界…");
+        assert!(is_safe_url("/relative#anchor"));
+        assert!(is_safe_url("HTTPS://example.com"));
+        assert!(!is_safe_url(" JavaScript:alert(1)"));
+        assert!(!is_safe_url("data:text/html,hi"));
+    }
+
+    #[test]
+    fn error_page() {
+        let page = render_error_page(&Error::NotFound("abc".to_string()));
+        assert!(page.contains("<title>404</title>"));
+        assert!(page.contains("no longer shared"));
+    }
+}
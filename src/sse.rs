@@ -0,0 +1,257 @@
+//! Decoder for `text/event-stream` bodies, following
+//! <https://html.spec.whatwg.org/multipage/server-sent-events.html#event-stream-interpretation>.
+
+/// One dispatched event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SseEvent {
+    /// `message` unless the server set `event:`
+    pub event: String,
+    /// `data:` lines joined by `\n`
+    pub data: String,
+    /// last event ID seen so far, which may come from an earlier event
+    pub id: Option<String>,
+    /// reconnection time in milliseconds last set with `retry:`, which may also come from an
+    /// earlier event. Upstream requests are never resumed, but clients relaying a stream may
+    /// pass it on.
+    pub retry: Option<u64>,
+}
+
+impl SseEvent {
+    /// Whether this is the `data: [DONE]` sentinel that ends OpenAI's streams.
+    pub fn is_done(&self) -> bool {
+        self.data == "[DONE]"
+    }
+}
+
+/// Decodes events from chunks of bytes, which may be split anywhere, including in the middle
+/// of a line or of a multibyte character.
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    /// bytes of the current, incomplete line
+    line: Vec<u8>,
+    /// the previous chunk ended with `\r`, so a leading `\n` belongs to the same line ending
+    after_cr: bool,
+    /// whether the byte order mark has been checked for
+    started: bool,
+    event: String,
+    data: String,
+    has_data: bool,
+    last_event_id: Option<String>,
+    retry: Option<u64>,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed the next chunk of the body, and return the events it completes.
+    pub fn push(&mut self, mut chunk: &[u8]) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+        if self.after_cr && !chunk.is_empty() {
+            self.after_cr = false;
+            if chunk[0] == b'\n' {
+                chunk = &chunk[1..];
+            }
+        }
+
+        let mut start = 0;
+        let mut i = 0;
+        while i < chunk.len() {
+            match chunk[i] {
+                b'\r' | b'\n' => {
+                    self.line.extend_from_slice(&chunk[start..i]);
+                    let line = std::mem::take(&mut self.line);
+                    events.extend(self.process_line(&line));
+                    if chunk[i] == b'\r' {
+                        match chunk.get(i + 1) {
+                            Some(b'\n') => i += 1,
+                            Some(_) => {}
+                            None => self.after_cr = true,
+                        }
+                    }
+                    i += 1;
+                    start = i;
+                }
+                _ => i += 1,
+            }
+        }
+        self.line.extend_from_slice(&chunk[start..]);
+        events
+    }
+
+    /// Call at the end of the body. Unlike the spec, which discards an event that isn't
+    /// terminated by a blank line, the pending event is dispatched, since some servers close
+    /// the stream right after the last `data:` line.
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            if let Some(event) = self.process_line(&line) {
+                return Some(event);
+            }
+        }
+        self.dispatch()
+    }
+
+    fn process_line(&mut self, line: &[u8]) -> Option<SseEvent> {
+        // only complete lines are decoded, so multibyte characters are never cut in half
+        let mut line = String::from_utf8_lossy(line);
+        if !self.started {
+            self.started = true;
+            if let Some(rest) = line.strip_prefix('\u{FEFF}') {
+                line = rest.to_string().into();
+            }
+        }
+
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') {
+            // comment, e.g. a keep-alive
+            return None;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_ref(), ""),
+        };
+        match field {
+            "event" => self.event = value.to_string(),
+            "data" => {
+                if self.has_data {
+                    self.data.push('\n');
+                }
+                self.data.push_str(value);
+                self.has_data = true;
+            }
+            "id" if !value.contains('\0') => self.last_event_id = Some(value.to_string()),
+            // only ASCII digits count, anything else is ignored
+            "retry" if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                if let Ok(retry) = value.parse() {
+                    self.retry = Some(retry);
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = std::mem::take(&mut self.event);
+        if !self.has_data {
+            return None;
+        }
+        self.has_data = false;
+        Some(SseEvent {
+            event: if event.is_empty() {
+                "message".to_string()
+            } else {
+                event
+            },
+            data: std::mem::take(&mut self.data),
+            id: self.last_event_id.clone(),
+            retry: self.retry,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(chunks: &[&[u8]]) -> Vec<SseEvent> {
+        let mut decoder = SseDecoder::new();
+        let mut events = Vec::new();
+        for chunk in chunks {
+            events.extend(decoder.push(chunk));
+        }
+        events.extend(decoder.finish());
+        events
+    }
+
+    fn message(data: &str) -> SseEvent {
+        SseEvent {
+            event: "message".to_string(),
+            data: data.to_string(),
+            id: None,
+            retry: None,
+        }
+    }
+
+    const MIXED: &str = "\u{FEFF}: keep-alive\r\n\
+        retry: 3000\r\n\
+        event: delta\r\n\
+        id: 1\r\n\
+        data: 你好\r\n\
+        data:世界\r\n\
+        \r\n\
+        data\r\r\
+        id: 2\n\
+        event: done\n\
+        data: [DONE]\n\n\
+        data: trailing";
+
+    #[test]
+    fn fields() {
+        let mut decoder = SseDecoder::new();
+        let events = decoder.push(MIXED.as_bytes());
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    event: "delta".to_string(),
+                    data: "你好\n世界".to_string(),
+                    id: Some("1".to_string()),
+                    retry: Some(3000),
+                },
+                SseEvent {
+                    event: "message".to_string(),
+                    data: "".to_string(),
+                    id: Some("1".to_string()),
+                    retry: Some(3000),
+                },
+                SseEvent {
+                    event: "done".to_string(),
+                    data: "[DONE]".to_string(),
+                    id: Some("2".to_string()),
+                    retry: Some(3000),
+                },
+            ]
+        );
+        assert!(events[2].is_done());
+        assert_eq!(
+            decoder.finish(),
+            Some(SseEvent {
+                id: Some("2".to_string()),
+                retry: Some(3000),
+                ..message("trailing")
+            })
+        );
+        assert_eq!(decoder.finish(), None);
+    }
+
+    #[test]
+    fn ignored_lines() {
+        assert_eq!(
+            decode_all(&[
+                b"event: ping\n\n: comment\nretry: soon\nfoo: bar\ndata:  two spaces\n\n"
+            ]),
+            vec![message(" two spaces")]
+        );
+    }
+
+    #[test]
+    fn split_at_every_offset() {
+        let bytes = MIXED.as_bytes();
+        let expected = decode_all(&[bytes]);
+        for i in 0..=bytes.len() {
+            assert_eq!(
+                decode_all(&[&bytes[..i], &bytes[i..]]),
+                expected,
+                "split at {}",
+                i
+            );
+        }
+        let bytewise: Vec<&[u8]> = bytes.chunks(1).collect();
+        assert_eq!(decode_all(&bytewise), expected);
+    }
+}
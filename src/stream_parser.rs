@@ -1,13 +1,20 @@
 use async_stream::try_stream;
-use futures_util::TryStream;
+use futures_util::Stream;
 use futures_util::{StreamExt, TryStreamExt};
 
-use std::str;
+use std::collections::VecDeque;
+use std::pin::Pin;
 use worker::ByteStream;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+use crate::input::InputError;
+use crate::provider::Provider;
+use crate::sse::{SseDecoder, SseEvent};
+use crate::tools::{ToolCall, ToolCallAccumulator, ToolCallDelta, ToolResult};
+use crate::usage::TokenUsage;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum FinishReason {
     #[serde(rename = "stop")]
     Stop,
@@ -17,95 +24,251 @@ pub enum FinishReason {
     ContentFilter,
     #[serde(rename = "unavailable")]
     Unavailable,
+    #[serde(rename = "timeout")]
+    Timeout, // the upstream stalled or took too long, see `Timeouts`
+    #[serde(rename = "tool_calls")]
+    ToolCalls, // the model called tools, and answers once it has their results
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
 pub enum StreamItem {
     RoleMsg,
     #[serde(rename = "start")]
     Start(u32), // token limit
+    #[serde(rename = "model")]
+    Model(String), // model answering the question
     #[serde(rename = "delta")]
     Delta(String),
     #[serde(rename = "finish")]
     Finish(FinishReason),
     #[serde(rename = "error")]
     Error(String),
+    #[serde(rename = "usage")]
+    Usage(TokenUsage),
+    #[serde(rename = "announcement")]
+    Announcement(String), // chat is refused, e.g. because the daily budget is used up
+    #[serde(rename = "input_error")]
+    InputError(InputError), // question is refused, e.g. because it is too long
+    #[serde(skip)]
+    ToolCallDelta(ToolCallDelta), // put together by the parser, never sent to the client
+    #[serde(rename = "tool_calls")]
+    ToolCalls(Vec<ToolCall>), // complete calls, sent before `Finish(ToolCalls)`
+    #[serde(rename = "tool_result")]
+    ToolResult(ToolResult),
 }
 
 impl StreamItem {
-    pub fn from_json_str(s: &str) -> Option<Self> {
-        if let Ok(s) = serde_json::from_str::<serde_json::Value>(s) {
-            let choice = s.get("choices")?.get(0)?;
-            let finish_reason = choice.get("finish_reason")?;
-            if finish_reason != &serde_json::Value::Null {
-                let finish_reason =
-                    serde_json::from_value::<FinishReason>(finish_reason.clone()).ok()?;
-                Some(StreamItem::Finish(finish_reason))
-            } else {
-                let delta = choice.get("delta")?;
-                if let Some(_) = delta.get("role") {
-                    Some(StreamItem::RoleMsg)
-                } else {
-                    let content = delta.get("content")?;
-                    let content = serde_json::from_value::<String>(content.clone()).ok()?;
-                    Some(StreamItem::Delta(content))
+    /// Decode the choice in a chunk of OpenAI's format.
+    pub fn from_json_value(s: &serde_json::Value) -> Option<Self> {
+        let choice = s.get("choices")?.get(0)?;
+        let finish_reason = choice.get("finish_reason")?;
+        if finish_reason != &serde_json::Value::Null {
+            let finish_reason =
+                serde_json::from_value::<FinishReason>(finish_reason.clone()).ok()?;
+            Some(StreamItem::Finish(finish_reason))
+        } else {
+            let delta = choice.get("delta")?;
+            // some OpenAI-compatible servers repeat `role` in every chunk, so content wins
+            match delta.get("content").and_then(|c| c.as_str()) {
+                Some(content) if !content.is_empty() => {
+                    Some(StreamItem::Delta(content.to_string()))
                 }
+                _ if delta.get("role").is_some() => Some(StreamItem::RoleMsg),
+                content => Some(StreamItem::Delta(content?.to_string())),
             }
-        } else {
-            None
         }
     }
 }
 
 impl From<crate::error::Error> for StreamItem {
     fn from(err: crate::error::Error) -> Self {
-        StreamItem::Error(err.to_string())
+        match err {
+            crate::error::Error::InvalidInput(input) => StreamItem::InputError(input),
+            err => StreamItem::Error(err.to_string()),
+        }
     }
 }
 
+/// Items decoded from the body of a streamed chat completion.
+pub type ChatStream = Pin<Box<dyn Stream<Item = worker::Result<StreamItem>>>>;
+
 pub struct ChatStreamParser {
-    buffer: String,
+    decoder: SseDecoder,
+    provider: Box<dyn Provider>,
+    /// decoded items not yet returned by `next`
+    decoded: VecDeque<StreamItem>,
+    /// tool calls streamed so far, decoded as a whole before the finish reason
+    tool_calls: ToolCallAccumulator,
+    /// whether `data: [DONE]` has been seen
+    done: bool,
 }
 
 impl ChatStreamParser {
-    pub fn new() -> Self {
+    pub fn new(provider: Box<dyn Provider>) -> Self {
         Self {
-            buffer: String::new(),
+            decoder: SseDecoder::new(),
+            provider,
+            decoded: VecDeque::new(),
+            tool_calls: ToolCallAccumulator::default(),
+            done: false,
         }
     }
 
     pub fn add_chunk(&mut self, chunk: &[u8]) {
-        if let Ok(chunk_str) = str::from_utf8(chunk) {
-            self.buffer.push_str(chunk_str);
+        for event in self.decoder.push(chunk) {
+            self.decode(event);
         }
     }
 
-    pub fn next(&mut self) -> Option<StreamItem> {
-        if let Some(start) = self.buffer.find("data:") {
-            let json_start = start + "data:".len();
-            let json_end = self.buffer[json_start..].find('\n').map(|i| json_start + i);
-            if let Some(end) = json_end {
-                let json_str = self.buffer[json_start..end].trim().to_string();
-                self.buffer.drain(..end);
-                return Some(StreamItem::from_json_str(&json_str)?);
+    /// Call at the end of the body, to decode an event the server didn't terminate.
+    pub fn finish(&mut self) {
+        if let Some(event) = self.decoder.finish() {
+            self.decode(event);
+        }
+        self.flush_tool_calls();
+    }
+
+    /// Whether the upstream has signalled the end of the stream. Anything after it is ignored.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn decode(&mut self, event: SseEvent) {
+        if self.done {
+            return;
+        }
+        if event.is_done() {
+            self.done = true;
+            self.flush_tool_calls();
+            return;
+        }
+        // events the provider has nothing to say about, e.g. pings, decode to nothing
+        for item in self.provider.decode_event(&event.data) {
+            match item {
+                StreamItem::ToolCallDelta(delta) => self.tool_calls.push(delta),
+                item => {
+                    if let StreamItem::Finish(_) = item {
+                        self.flush_tool_calls();
+                    }
+                    self.decoded.push_back(item);
+                }
             }
         }
-        None
     }
 
-    pub fn parse_byte_stream(
-        stream: ByteStream,
-    ) -> impl TryStream<Item = worker::Result<StreamItem>> + Unpin {
+    fn flush_tool_calls(&mut self) {
+        let calls = self.tool_calls.take();
+        if !calls.is_empty() {
+            self.decoded.push_back(StreamItem::ToolCalls(calls));
+        }
+    }
+
+    pub fn next(&mut self) -> Option<StreamItem> {
+        self.decoded.pop_front()
+    }
+
+    pub fn parse_byte_stream(stream: ByteStream, provider: Box<dyn Provider>) -> ChatStream {
         Box::pin(try_stream! {
-            let mut parser = ChatStreamParser::new();
+            let mut parser = ChatStreamParser::new(provider);
             let mut stream = stream.into_stream();
             while let Some(chunk) = stream.next().await {
                 let chunk = chunk?;
                 parser.add_chunk(&chunk);
-                while let Some(json) = parser.next() {
-                    yield json;
+                while let Some(item) = parser.next() {
+                    yield item;
+                }
+                if parser.is_done() {
+                    break;
                 }
             }
+            parser.finish();
+            while let Some(item) = parser.next() {
+                yield item;
+            }
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::ProviderConfig;
+
+    fn decode_chunks(provider: &ProviderConfig, chunks: &[&[u8]]) -> Vec<StreamItem> {
+        let mut parser = ChatStreamParser::new(provider.build());
+        let mut items = Vec::new();
+        for chunk in chunks {
+            parser.add_chunk(chunk);
+            while let Some(item) = parser.next() {
+                items.push(item);
+            }
+        }
+        parser.finish();
+        while let Some(item) = parser.next() {
+            items.push(item);
+        }
+        items
+    }
+
+    #[test]
+    fn fixtures_split_at_every_offset() {
+        let fixtures = [
+            (ProviderConfig::OpenAI, include_str!("fixtures/openai.sse")),
+            (
+                ProviderConfig::Anthropic,
+                include_str!("fixtures/anthropic.sse"),
+            ),
+        ];
+        for (provider, fixture) in fixtures.iter() {
+            // CRLF line endings and a non-ASCII answer
+            let fixture = fixture.replace("Hello", "你好").replace('\n', "\r\n");
+            let bytes = fixture.as_bytes();
+            let expected = decode_chunks(provider, &[bytes]);
+            assert!(expected.contains(&StreamItem::Delta("你好".to_string())));
+            for i in 0..=bytes.len() {
+                assert_eq!(
+                    decode_chunks(provider, &[&bytes[..i], &bytes[i..]]),
+                    expected,
+                    "split at {}",
+                    i
+                );
+            }
+            let bytewise: Vec<&[u8]> = bytes.chunks(1).collect();
+            assert_eq!(decode_chunks(provider, &bytewise), expected);
+        }
+    }
+
+    #[test]
+    fn done_and_malformed_events() {
+        let body = "data: not json\n\n\
+            data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"},\"finish_reason\":null}]}\n\n\
+            data: [DONE]\n\n\
+            data: {\"choices\":[{\"delta\":{\"content\":\"late\"},\"finish_reason\":null}]}\n\n";
+        let mut parser = ChatStreamParser::new(ProviderConfig::OpenAI.build());
+        parser.add_chunk(body.as_bytes());
+        assert!(parser.is_done());
+        assert_eq!(parser.next(), Some(StreamItem::Delta("Hi".to_string())));
+        assert_eq!(parser.next(), None);
+    }
+
+    #[test]
+    fn streamed_tool_calls() {
+        let body = "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\",\"content\":null,\"tool_calls\":[{\"index\":0,\"id\":\"call_a\",\"type\":\"function\",\"function\":{\"name\":\"lookup_faq\",\"arguments\":\"\"}}]},\"finish_reason\":null}]}\n\n\
+            data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"{\\\"query\\\":\\\"work\\\"}\"}}]},\"finish_reason\":null}]}\n\n\
+            data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"tool_calls\"}]}\n\n\
+            data: [DONE]\n\n";
+        let items = decode_chunks(&ProviderConfig::OpenAI, &[body.as_bytes()]);
+        assert_eq!(
+            items,
+            vec![
+                StreamItem::RoleMsg,
+                StreamItem::ToolCalls(vec![ToolCall {
+                    id: "call_a".to_string(),
+                    name: "lookup_faq".to_string(),
+                    arguments: "{\"query\":\"work\"}".to_string(),
+                }]),
+                StreamItem::Finish(FinishReason::ToolCalls),
+            ]
+        );
+    }
+}
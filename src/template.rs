@@ -0,0 +1,343 @@
+//! Placeholders and conditionals in prompt messages.
+//!
+//! `[NAME]` is replaced by the variable `NAME`. Names are upper snake case, so Markdown such as
+//! `[my blog](https://tomshen.io)` is left alone. Conditionals look like
+//!
+//! ```text
+//! [IF COUNTRY == "CN"]...[ELSE]...[END]
+//! [IF COUNTRY IN "CN, HK, TW"]...[END]
+//! [IF CITY]...[END]
+//! ```
+//!
+//! where a bare name is true if the variable is set and not empty. Conditionals can be nested.
+
+use std::collections::{BTreeSet, HashMap};
+
+/// Variables available in every prompt, on top of `Config.variables`.
+pub const BUILTIN_VARIABLES: [&str; 7] = [
+    "CURRENT_TIME",
+    "CURRENT_DATE",
+    "COUNTRY",
+    "CITY",
+    "SITE_NAME",
+    "QUESTIONS",
+    "KNOWLEDGE",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Condition {
+    NonEmpty(String),
+    Equals(String, String),
+    NotEquals(String, String),
+    In(String, Vec<String>),
+}
+
+impl Condition {
+    fn parse(expr: &str) -> Result<Self, String> {
+        let expr = expr.trim();
+        let quoted = |value: &str| {
+            let value = value.trim();
+            value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .map(|v| v.to_string())
+                .ok_or_else(|| format!("expected a quoted value in [IF {}]", expr))
+        };
+        let name = |name: &str| {
+            let name = name.trim();
+            if is_variable_name(name) {
+                Ok(name.to_string())
+            } else {
+                Err(format!("invalid variable name `{}` in [IF {}]", name, expr))
+            }
+        };
+
+        if let Some((left, right)) = expr.split_once("!=") {
+            Ok(Condition::NotEquals(name(left)?, quoted(right)?))
+        } else if let Some((left, right)) = expr.split_once("==") {
+            Ok(Condition::Equals(name(left)?, quoted(right)?))
+        } else if let Some((left, right)) = expr.split_once(" IN ") {
+            let values = quoted(right)?
+                .split(',')
+                .map(|v| v.trim().to_string())
+                .collect();
+            Ok(Condition::In(name(left)?, values))
+        } else {
+            Ok(Condition::NonEmpty(name(expr)?))
+        }
+    }
+
+    fn variable(&self) -> &str {
+        match self {
+            Condition::NonEmpty(name)
+            | Condition::Equals(name, _)
+            | Condition::NotEquals(name, _)
+            | Condition::In(name, _) => name,
+        }
+    }
+
+    fn eval(&self, variables: &HashMap<String, String>) -> bool {
+        let value = variables
+            .get(self.variable())
+            .map(|v| v.as_str())
+            .unwrap_or("");
+        match self {
+            Condition::NonEmpty(_) => !value.is_empty(),
+            Condition::Equals(_, expected) => value == expected,
+            Condition::NotEquals(_, expected) => value != expected,
+            Condition::In(_, values) => values.iter().any(|v| v == value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Text(String),
+    Variable(String),
+    If {
+        condition: Condition,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+}
+
+enum Tag {
+    Variable(String),
+    If(Condition),
+    Else,
+    End,
+}
+
+/// Whether `name` can be used as `[name]`, i.e. is UPPER_SNAKE_CASE.
+pub fn is_variable_name(name: &str) -> bool {
+    name.len() > 1
+        && name
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Interpret the inside of `[...]`. Returns `Ok(None)` if it is not a tag at all.
+fn parse_tag(inner: &str) -> Result<Option<Tag>, String> {
+    if let Some(expr) = inner.strip_prefix("IF ") {
+        return Ok(Some(Tag::If(Condition::parse(expr)?)));
+    }
+    Ok(match inner {
+        "ELSE" => Some(Tag::Else),
+        "END" => Some(Tag::End),
+        name if is_variable_name(name) => Some(Tag::Variable(name.to_string())),
+        _ => None,
+    })
+}
+
+/// A parsed prompt message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        // stack of open blocks: the condition, the nodes of its `then` branch if we are past
+        // `[ELSE]`, and the nodes being collected
+        let mut stack: Vec<(Condition, Option<Vec<Node>>, Vec<Node>)> = Vec::new();
+        let mut nodes = Vec::new();
+        let mut rest = source;
+        let mut text = String::new();
+
+        while let Some(open) = rest.find('[') {
+            let close = match rest[open..].find(']') {
+                Some(close) => open + close,
+                None => break,
+            };
+            let tag = match parse_tag(&rest[open + 1..close])? {
+                Some(tag) => tag,
+                None => {
+                    // not ours, e.g. a Markdown link
+                    text.push_str(&rest[..open + 1]);
+                    rest = &rest[open + 1..];
+                    continue;
+                }
+            };
+            text.push_str(&rest[..open]);
+            rest = &rest[close + 1..];
+            if !text.is_empty() {
+                nodes.push(Node::Text(std::mem::take(&mut text)));
+            }
+
+            match tag {
+                Tag::Variable(name) => nodes.push(Node::Variable(name)),
+                Tag::If(condition) => {
+                    stack.push((condition, None, std::mem::take(&mut nodes)));
+                }
+                Tag::Else => match stack.last_mut() {
+                    Some((_, then @ None, _)) => *then = Some(std::mem::take(&mut nodes)),
+                    Some(_) => return Err("[ELSE] appears twice in one [IF]".to_string()),
+                    None => return Err("[ELSE] without [IF]".to_string()),
+                },
+                Tag::End => {
+                    let (condition, then, parent) = stack
+                        .pop()
+                        .ok_or_else(|| "[END] without [IF]".to_string())?;
+                    let branch = std::mem::replace(&mut nodes, parent);
+                    let (then, otherwise) = match then {
+                        Some(then) => (then, branch),
+                        None => (branch, Vec::new()),
+                    };
+                    nodes.push(Node::If {
+                        condition,
+                        then,
+                        otherwise,
+                    });
+                }
+            }
+        }
+
+        if !stack.is_empty() {
+            return Err("[IF] without [END]".to_string());
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            nodes.push(Node::Text(text));
+        }
+        Ok(Self { nodes })
+    }
+
+    /// Names of all variables used, in placeholders or conditions.
+    pub fn variables(&self) -> BTreeSet<&str> {
+        fn collect<'a>(nodes: &'a [Node], names: &mut BTreeSet<&'a str>) {
+            for node in nodes {
+                match node {
+                    Node::Text(_) => {}
+                    Node::Variable(name) => {
+                        names.insert(name);
+                    }
+                    Node::If {
+                        condition,
+                        then,
+                        otherwise,
+                    } => {
+                        names.insert(condition.variable());
+                        collect(then, names);
+                        collect(otherwise, names);
+                    }
+                }
+            }
+        }
+        let mut names = BTreeSet::new();
+        collect(&self.nodes, &mut names);
+        names
+    }
+
+    /// Unknown variables are left as they are, so a mistake shows up in the prompt rather than
+    /// silently disappearing.
+    pub fn render(&self, variables: &HashMap<String, String>) -> String {
+        fn render_nodes(nodes: &[Node], variables: &HashMap<String, String>, out: &mut String) {
+            for node in nodes {
+                match node {
+                    Node::Text(text) => out.push_str(text),
+                    Node::Variable(name) => match variables.get(name) {
+                        Some(value) => out.push_str(value),
+                        None => {
+                            out.push('[');
+                            out.push_str(name);
+                            out.push(']');
+                        }
+                    },
+                    Node::If {
+                        condition,
+                        then,
+                        otherwise,
+                    } => {
+                        let branch = if condition.eval(variables) {
+                            then
+                        } else {
+                            otherwise
+                        };
+                        render_nodes(branch, variables, out);
+                    }
+                }
+            }
+        }
+        let mut out = String::new();
+        render_nodes(&self.nodes, variables, &mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn render(source: &str, pairs: &[(&str, &str)]) -> String {
+        Template::parse(source).unwrap().render(&variables(pairs))
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(
+            render(
+                "It is [CURRENT_TIME] in [CITY]. See [my blog](https://tomshen.io) [A].",
+                &[
+                    ("CURRENT_TIME", "2023-05-01 9:30:00"),
+                    ("CITY", "Palo Alto")
+                ]
+            ),
+            "It is 2023-05-01 9:30:00 in Palo Alto. See [my blog](https://tomshen.io) [A]."
+        );
+        assert_eq!(render("[UNKNOWN] stays", &[]), "[UNKNOWN] stays");
+        assert_eq!(render("no tags [", &[]), "no tags [");
+    }
+
+    #[test]
+    fn conditionals() {
+        let source =
+            r#"Hi.[IF COUNTRY == "CN"] 你好。[ELSE] Hello.[END][IF CITY] From [CITY].[END]"#;
+        assert_eq!(
+            render(source, &[("COUNTRY", "CN"), ("CITY", "")]),
+            "Hi. 你好。"
+        );
+        assert_eq!(
+            render(source, &[("COUNTRY", "US"), ("CITY", "Seattle")]),
+            "Hi. Hello. From Seattle."
+        );
+
+        let source =
+            r#"[IF COUNTRY IN "CN, HK, TW"]zh[IF CITY != "Beijing"]-[CITY][END][ELSE]en[END]"#;
+        assert_eq!(
+            render(source, &[("COUNTRY", "HK"), ("CITY", "Hong Kong")]),
+            "zh-Hong Kong"
+        );
+        assert_eq!(
+            render(source, &[("COUNTRY", "CN"), ("CITY", "Beijing")]),
+            "zh"
+        );
+        assert_eq!(render(source, &[("COUNTRY", "JP")]), "en");
+    }
+
+    #[test]
+    fn variables_used() {
+        let template =
+            Template::parse(r#"[SITE_NAME][IF COUNTRY == "CN"][GREETING_CN][END]"#).unwrap();
+        assert_eq!(
+            template.variables().into_iter().collect::<Vec<_>>(),
+            vec!["COUNTRY", "GREETING_CN", "SITE_NAME"]
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(Template::parse("[IF COUNTRY]").is_err());
+        assert!(Template::parse("[END]").is_err());
+        assert!(Template::parse("[ELSE]").is_err());
+        assert!(Template::parse("[IF COUNTRY]a[ELSE]b[ELSE]c[END]").is_err());
+        assert!(Template::parse("[IF COUNTRY == CN]a[END]").is_err());
+        assert!(Template::parse("[IF country]a[END]").is_err());
+    }
+}
@@ -0,0 +1,573 @@
+//! Tools the model can call, run by the worker: the current time, a FAQ lookup, and the latest
+//! posts of a blog. Calls stream in OpenAI's format and are put together by the stream parser;
+//! their results go back to the model until it answers or runs out of rounds.
+
+use chrono::{Datelike, Timelike};
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use worker::{Fetch, Url};
+
+use crate::error::{Error, Result};
+
+/// most rounds of tool calls `max_iterations` can be set to
+pub const MAX_ITERATIONS_LIMIT: u32 = 10;
+const DEFAULT_BLOG_POSTS: u64 = 5;
+const MAX_BLOG_POSTS: u64 = 20;
+const FAQ_MATCHES: usize = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ToolsConfig {
+    /// JSON Feed of blog posts, for `list_blog_posts`, which is only offered with one
+    pub blog_feed_url: Option<String>,
+    /// entries for `lookup_faq`, which is only offered with some
+    pub faq: Vec<FaqEntry>,
+    /// rounds of tool calls per question, after which the model has to answer
+    pub max_iterations: u32,
+}
+
+impl Default for ToolsConfig {
+    fn default() -> Self {
+        Self {
+            blog_feed_url: None,
+            faq: Vec::new(),
+            max_iterations: 3,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FaqEntry {
+    pub question: String,
+    pub answer: String,
+}
+
+/// A complete call of a tool by the model.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    /// JSON object, as generated by the model
+    pub arguments: String,
+}
+
+/// A piece of a tool call, as streamed in `delta.tool_calls`. Only the first piece of a call
+/// has its id and name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub arguments: String,
+}
+
+impl ToolCallDelta {
+    /// Pieces of tool calls in a choice delta of OpenAI's format.
+    pub fn from_delta(delta: &Value) -> Vec<Self> {
+        let calls = match delta.get("tool_calls").and_then(|calls| calls.as_array()) {
+            Some(calls) => calls,
+            None => return Vec::new(),
+        };
+        calls
+            .iter()
+            .filter_map(|call| {
+                let string = |value: Option<&Value>| value?.as_str().map(str::to_string);
+                let function = call.get("function");
+                Some(Self {
+                    index: call.get("index")?.as_u64()? as usize,
+                    id: string(call.get("id")),
+                    name: string(function.and_then(|f| f.get("name"))),
+                    arguments: string(function.and_then(|f| f.get("arguments")))
+                        .unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Put streamed pieces together into calls, in the order the model made them.
+#[derive(Debug, Default)]
+pub struct ToolCallAccumulator {
+    calls: Vec<(usize, ToolCall)>,
+}
+
+impl ToolCallAccumulator {
+    pub fn push(&mut self, delta: ToolCallDelta) {
+        let i = match self
+            .calls
+            .iter()
+            .position(|(index, _)| *index == delta.index)
+        {
+            Some(i) => i,
+            None => {
+                self.calls.push((delta.index, ToolCall::default()));
+                self.calls.len() - 1
+            }
+        };
+        let call = &mut self.calls[i].1;
+        if let Some(id) = delta.id {
+            call.id = id;
+        }
+        if let Some(name) = delta.name {
+            call.name.push_str(&name);
+        }
+        call.arguments.push_str(&delta.arguments);
+    }
+
+    pub fn take(&mut self) -> Vec<ToolCall> {
+        std::mem::take(&mut self.calls)
+            .into_iter()
+            .map(|(_, call)| call)
+            .collect()
+    }
+}
+
+/// How a tool call went, as told to the client. What the tool returned only goes to the model.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ToolResult {
+    pub id: String,
+    pub name: String,
+    pub success: bool,
+}
+
+/// Messages of one round of tool calls for OpenAI's format: the assistant's calls, along with
+/// any text it wrote before them, then the output of each call.
+pub fn round_messages(content: &str, calls: &[ToolCall], outputs: &[String]) -> Vec<Value> {
+    let tool_calls: Vec<Value> = calls
+        .iter()
+        .map(|call| {
+            json!({
+                "id": call.id,
+                "type": "function",
+                "function": { "name": call.name, "arguments": call.arguments },
+            })
+        })
+        .collect();
+    let content = if content.is_empty() {
+        Value::Null
+    } else {
+        json!(content)
+    };
+    std::iter::once(json!({
+        "role": "assistant",
+        "content": content,
+        "tool_calls": tool_calls,
+    }))
+    .chain(calls.iter().zip(outputs).map(|(call, output)| {
+        json!({
+            "role": "tool",
+            "tool_call_id": call.id,
+            "content": output,
+        })
+    }))
+    .collect()
+}
+
+trait Tool {
+    fn name(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    /// JSON schema of the arguments
+    fn parameters(&self) -> Value;
+    fn call<'a>(&'a self, arguments: &'a Value) -> LocalBoxFuture<'a, Result<String>>;
+}
+
+struct CurrentTime {
+    timezone: chrono::FixedOffset,
+    /// seconds since epoch, taken when the question came in
+    now: i64,
+}
+
+impl Tool for CurrentTime {
+    fn name(&self) -> &'static str {
+        "current_time"
+    }
+
+    fn description(&self) -> &'static str {
+        "Get the current date and time in the visitor's timezone."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({ "type": "object", "properties": {} })
+    }
+
+    fn call<'a>(&'a self, _arguments: &'a Value) -> LocalBoxFuture<'a, Result<String>> {
+        let result = chrono::DateTime::from_timestamp(self.now, 0)
+            .map(|time| {
+                let time = time.with_timezone(&self.timezone);
+                format!(
+                    "{:04}-{:02}-{:02} {:02}:{:02} (UTC{})",
+                    time.year(),
+                    time.month(),
+                    time.day(),
+                    time.hour(),
+                    time.minute(),
+                    self.timezone
+                )
+            })
+            .ok_or_else(|| Error::InternalError("clock out of range".to_string()));
+        futures_util::future::ready(result).boxed_local()
+    }
+}
+
+struct FaqLookup {
+    entries: Vec<FaqEntry>,
+}
+
+fn words(s: &str) -> Vec<String> {
+    s.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+impl FaqLookup {
+    /// Entries sharing the most words with `query`, best first.
+    fn search(&self, query: &str) -> Vec<&FaqEntry> {
+        let query = words(query);
+        let mut scored: Vec<(usize, &FaqEntry)> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let shared = words(&entry.question)
+                    .iter()
+                    .filter(|word| query.contains(word))
+                    .count();
+                (shared, entry)
+            })
+            .filter(|(shared, _)| *shared > 0)
+            .collect();
+        // stable, so ties keep the order of the config
+        scored.sort_by_key(|(shared, _)| std::cmp::Reverse(*shared));
+        scored
+            .into_iter()
+            .take(FAQ_MATCHES)
+            .map(|(_, entry)| entry)
+            .collect()
+    }
+}
+
+impl Tool for FaqLookup {
+    fn name(&self) -> &'static str {
+        "lookup_faq"
+    }
+
+    fn description(&self) -> &'static str {
+        "Look up answers to frequently asked questions about the site owner."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "query": { "type": "string", "description": "the question, or keywords of it" },
+            },
+            "required": ["query"],
+        })
+    }
+
+    fn call<'a>(&'a self, arguments: &'a Value) -> LocalBoxFuture<'a, Result<String>> {
+        let result = match arguments.get("query").and_then(|query| query.as_str()) {
+            Some(query) => {
+                let entries = self.search(query);
+                Ok(if entries.is_empty() {
+                    "No FAQ entry matches.".to_string()
+                } else {
+                    entries
+                        .iter()
+                        .map(|entry| format!("Q: {}\nA: {}", entry.question, entry.answer))
+                        .collect::<Vec<_>>()
+                        .join("\n\n")
+                })
+            }
+            None => Err(Error::InvalidRequest("`query` is missing".to_string())),
+        };
+        futures_util::future::ready(result).boxed_local()
+    }
+}
+
+struct BlogPosts {
+    feed_url: String,
+}
+
+/// The latest `limit` posts of a JSON Feed, one per line.
+fn format_feed(feed: &str, limit: usize) -> Result<String> {
+    #[derive(Deserialize)]
+    struct Item {
+        title: Option<String>,
+        url: Option<String>,
+        date_published: Option<String>,
+        summary: Option<String>,
+    }
+    #[derive(Deserialize)]
+    struct Feed {
+        items: Vec<Item>,
+    }
+
+    let feed: Feed = serde_json::from_str(feed)
+        .map_err(|e| Error::InternalError(format!("malformed blog feed: {}", e)))?;
+    let posts: Vec<String> = feed
+        .items
+        .into_iter()
+        .take(limit)
+        .map(|item| {
+            let mut post = format!("- {}", item.title.as_deref().unwrap_or("Untitled"));
+            if let Some(date) = item.date_published {
+                // RFC 3339, of which the date is enough
+                post.push_str(&format!(" ({})", date.get(..10).unwrap_or(&date)));
+            }
+            if let Some(url) = item.url {
+                post.push_str(&format!(": {}", url));
+            }
+            if let Some(summary) = item.summary {
+                post.push_str(&format!("\n  {}", summary));
+            }
+            post
+        })
+        .collect();
+    Ok(if posts.is_empty() {
+        "The blog has no posts.".to_string()
+    } else {
+        posts.join("\n")
+    })
+}
+
+impl Tool for BlogPosts {
+    fn name(&self) -> &'static str {
+        "list_blog_posts"
+    }
+
+    fn description(&self) -> &'static str {
+        "List the latest posts of the site owner's blog, newest first."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "limit": {
+                    "type": "integer",
+                    "description": "how many posts to list",
+                    "minimum": 1,
+                    "maximum": MAX_BLOG_POSTS,
+                },
+            },
+        })
+    }
+
+    fn call<'a>(&'a self, arguments: &'a Value) -> LocalBoxFuture<'a, Result<String>> {
+        async move {
+            let limit = arguments
+                .get("limit")
+                .and_then(|limit| limit.as_u64())
+                .unwrap_or(DEFAULT_BLOG_POSTS)
+                .clamp(1, MAX_BLOG_POSTS);
+            let url = Url::parse(&self.feed_url)
+                .map_err(|e| Error::InternalError(format!("bad blog feed URL: {}", e)))?;
+            let mut response = Fetch::Url(url).send().await?;
+            if response.status_code() != 200 {
+                return Err(Error::InternalError(format!(
+                    "blog feed answered with {}",
+                    response.status_code()
+                )));
+            }
+            format_feed(&response.text().await?, limit as usize)
+        }
+        .boxed_local()
+    }
+}
+
+/// The tools offered to the model for one question.
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn new(config: &ToolsConfig, timezone: chrono::FixedOffset, now: i64) -> Self {
+        let mut tools: Vec<Box<dyn Tool>> = vec![Box::new(CurrentTime { timezone, now })];
+        if !config.faq.is_empty() {
+            tools.push(Box::new(FaqLookup {
+                entries: config.faq.clone(),
+            }));
+        }
+        if let Some(feed_url) = &config.blog_feed_url {
+            tools.push(Box::new(BlogPosts {
+                feed_url: feed_url.clone(),
+            }));
+        }
+        Self { tools }
+    }
+
+    /// The `tools` field of a request in OpenAI's format.
+    pub fn definitions(&self) -> Vec<Value> {
+        self.tools
+            .iter()
+            .map(|tool| {
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": tool.name(),
+                        "description": tool.description(),
+                        "parameters": tool.parameters(),
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// Run a call. Failures are returned as the output, so that the model can tell the visitor
+    /// or try something else.
+    pub async fn run(&self, call: &ToolCall) -> (String, bool) {
+        let result = async {
+            let tool = self
+                .tools
+                .iter()
+                .find(|tool| tool.name() == call.name)
+                .ok_or_else(|| Error::InvalidRequest(format!("no tool named {}", call.name)))?;
+            let arguments: Value = if call.arguments.trim().is_empty() {
+                json!({})
+            } else {
+                serde_json::from_str(&call.arguments)
+                    .map_err(|e| Error::InvalidRequest(format!("malformed arguments: {}", e)))?
+            };
+            tool.call(&arguments).await
+        }
+        .await;
+        match result {
+            Ok(output) => (output, true),
+            Err(e) => (format!("error: {}", e), false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> ToolRegistry {
+        let config = ToolsConfig {
+            faq: vec![
+                FaqEntry {
+                    question: "Where does Tom work?".to_string(),
+                    answer: "At a startup.".to_string(),
+                },
+                FaqEntry {
+                    question: "What languages does Tom speak?".to_string(),
+                    answer: "English and Chinese.".to_string(),
+                },
+            ],
+            ..ToolsConfig::default()
+        };
+        // UTC+8, 2023-11-14 22:13:20 UTC
+        let timezone = chrono::FixedOffset::east_opt(8 * 3600).unwrap();
+        ToolRegistry::new(&config, timezone, 1_700_000_000)
+    }
+
+    fn run(registry: &ToolRegistry, name: &str, arguments: &str) -> (String, bool) {
+        let call = ToolCall {
+            id: "call_1".to_string(),
+            name: name.to_string(),
+            arguments: arguments.to_string(),
+        };
+        registry.run(&call).now_or_never().unwrap()
+    }
+
+    #[test]
+    fn accumulate_streamed_calls() {
+        let deltas = [
+            json!({"role": "assistant", "content": null, "tool_calls": [{"index": 0, "id": "call_a", "type": "function", "function": {"name": "lookup_faq", "arguments": ""}}]}),
+            json!({"tool_calls": [{"index": 0, "function": {"arguments": "{\"query\":"}}]}),
+            json!({"tool_calls": [{"index": 1, "id": "call_b", "type": "function", "function": {"name": "current_time", "arguments": "{}"}}]}),
+            json!({"tool_calls": [{"index": 0, "function": {"arguments": " \"work\"}"}}]}),
+            json!({"content": "no calls"}),
+        ];
+        let mut accumulator = ToolCallAccumulator::default();
+        for delta in deltas.iter() {
+            for piece in ToolCallDelta::from_delta(delta) {
+                accumulator.push(piece);
+            }
+        }
+        assert_eq!(
+            accumulator.take(),
+            vec![
+                ToolCall {
+                    id: "call_a".to_string(),
+                    name: "lookup_faq".to_string(),
+                    arguments: "{\"query\": \"work\"}".to_string(),
+                },
+                ToolCall {
+                    id: "call_b".to_string(),
+                    name: "current_time".to_string(),
+                    arguments: "{}".to_string(),
+                },
+            ]
+        );
+        assert!(accumulator.take().is_empty());
+    }
+
+    #[test]
+    fn run_tools() {
+        let registry = registry();
+        let names: Vec<Value> = registry
+            .definitions()
+            .iter()
+            .map(|tool| tool["function"]["name"].clone())
+            .collect();
+        assert_eq!(names, vec![json!("current_time"), json!("lookup_faq")]);
+
+        assert_eq!(
+            run(&registry, "current_time", ""),
+            ("2023-11-15 06:13 (UTC+08:00)".to_string(), true)
+        );
+        assert_eq!(
+            run(&registry, "lookup_faq", r#"{"query": "where does he work"}"#),
+            (
+                "Q: Where does Tom work?\nA: At a startup.\n\nQ: What languages does Tom speak?\nA: English and Chinese."
+                    .to_string(),
+                true
+            )
+        );
+        assert_eq!(
+            run(&registry, "lookup_faq", r#"{"query": "hobbies"}"#).0,
+            "No FAQ entry matches."
+        );
+        assert!(!run(&registry, "lookup_faq", "{").1);
+        assert!(!run(&registry, "lookup_faq", "{}").1);
+        let (output, success) = run(&registry, "list_blog_posts", "{}");
+        assert!(!success);
+        assert!(output.contains("no tool named list_blog_posts"));
+    }
+
+    #[test]
+    fn blog_feed() {
+        let feed = r#"{"version": "https://jsonfeed.org/version/1.1", "items": [
+            {"id": "1", "title": "Salieri", "url": "https://tomshen.io/salieri", "date_published": "2023-04-01T10:00:00+08:00", "summary": "A chatbot."},
+            {"id": "2", "title": "Rust on Workers", "url": "https://tomshen.io/rust"}
+        ]}"#;
+        assert_eq!(
+            format_feed(feed, 5).unwrap(),
+            "- Salieri (2023-04-01): https://tomshen.io/salieri\n  A chatbot.\n- Rust on Workers: https://tomshen.io/rust"
+        );
+        assert_eq!(format_feed(feed, 1).unwrap().lines().count(), 2);
+        assert!(format_feed("[]", 5).is_err());
+    }
+
+    #[test]
+    fn messages_of_a_round() {
+        let call = ToolCall {
+            id: "call_a".to_string(),
+            name: "current_time".to_string(),
+            arguments: "{}".to_string(),
+        };
+        let messages = round_messages("", &[call], &["12:00".to_string()]);
+        assert_eq!(messages[0]["content"], Value::Null);
+        assert_eq!(
+            messages[0]["tool_calls"][0]["function"]["name"],
+            "current_time"
+        );
+        assert_eq!(
+            messages[1],
+            json!({"role": "tool", "tool_call_id": "call_a", "content": "12:00"})
+        );
+    }
+}
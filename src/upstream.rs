@@ -0,0 +1,167 @@
+use futures_util::{stream, StreamExt};
+use worker::{
+    console_log, wasm_bindgen::JsValue, Date, Fetch, Headers, Method, Request, RequestInit,
+};
+
+use crate::error::{Error, Result};
+use crate::prompt::RequestToOpenAI;
+use crate::provider::{Attempt, Target};
+use crate::stream_parser::{ChatStream, ChatStreamParser, StreamItem};
+use crate::utils::timeout;
+
+/// A target together with the api key of its provider.
+#[derive(Clone)]
+pub struct Upstream {
+    pub target: Target,
+    pub api_key: Option<String>,
+}
+
+/// `upstreams` with the first one serving `model` instead, e.g. the model of a prompt variant.
+pub fn with_primary_model(upstreams: &[Upstream], model: &str) -> Vec<Upstream> {
+    let mut upstreams = upstreams.to_vec();
+    if let Some(primary) = upstreams.first_mut() {
+        primary.target.model = model.to_string();
+    }
+    upstreams
+}
+
+/// A completion that has started streaming.
+pub struct Completion {
+    /// model that is answering
+    pub model: String,
+    pub stream: ChatStream,
+    /// every target tried, the answering one last
+    pub attempts: Vec<Attempt>,
+}
+
+/// Whether the next target should be tried after a failed `attempt`: one that got no response
+/// at all, or a 429 or 5xx.
+fn is_retryable(attempt: &Attempt) -> bool {
+    attempt
+        .status
+        .is_none_or(|status| status == 429 || status >= 500)
+}
+
+/// Send `request` to the first upstream that answers. An upstream is skipped if it can't be
+/// reached, responds with 429 or 5xx, or if its first delta doesn't arrive within
+/// `first_token_ms`.
+pub async fn start_completion(
+    upstreams: &[Upstream],
+    request: &RequestToOpenAI,
+    first_token_ms: Option<u64>,
+) -> Result<Completion> {
+    let mut attempts = Vec::new();
+    let mut last_error = Error::InternalError("no upstream configured".to_string());
+
+    'targets: for upstream in upstreams {
+        let target = &upstream.target;
+        let mut attempt = Attempt {
+            provider: target.provider.name().to_string(),
+            model: target.model.clone(),
+            status: None,
+            timed_out: false,
+        };
+        let provider = target.provider.build();
+        let mut request = request.clone();
+        request.model = target.model.clone();
+
+        let mut headers = Headers::new();
+        for (name, value) in provider.headers(upstream.api_key.as_deref()) {
+            headers.append(name, &value)?;
+        }
+
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post);
+        init.with_headers(headers);
+        let body = serde_json::to_string(&provider.request_body(&request))?;
+        init.with_body(Some(JsValue::from_str(&body)));
+
+        let fetch_request = Request::new_with_init(&provider.endpoint(), &init)?;
+
+        let deadline = first_token_ms.map(|ms| Date::now().as_millis() + ms);
+        let remaining = || deadline.map(|d| d.saturating_sub(Date::now().as_millis()));
+
+        let mut response = match timeout(Fetch::Request(fetch_request).send(), remaining()).await {
+            Some(Ok(response)) => response,
+            // no response at all, e.g. a DNS, TLS or connection error
+            Some(Err(e)) => {
+                attempts.push(attempt);
+                last_error = e.into();
+                continue;
+            }
+            None => {
+                attempt.timed_out = true;
+                attempts.push(attempt);
+                last_error = Error::UpstreamTimeout;
+                continue;
+            }
+        };
+        let status = response.status_code();
+        attempt.status = Some(status);
+        if status != 200 {
+            let retryable = is_retryable(&attempt);
+            attempts.push(attempt);
+            last_error = Error::OpenAIError(status, response.text().await?);
+            if retryable {
+                continue;
+            }
+            break;
+        }
+
+        let mut body = ChatStreamParser::parse_byte_stream(response.stream()?, provider);
+
+        // hold back everything up to the first delta, so that the next target can still take
+        // over if this one stalls
+        let mut pending = Vec::new();
+        loop {
+            match timeout(body.next(), remaining()).await {
+                Some(Some(Ok(StreamItem::RoleMsg))) => continue,
+                Some(Some(Ok(StreamItem::Usage(usage)))) => {
+                    pending.push(Ok(StreamItem::Usage(usage)));
+                }
+                Some(Some(item)) => {
+                    pending.push(item);
+                    break;
+                }
+                Some(None) => break,
+                None => {
+                    attempt.timed_out = true;
+                    attempts.push(attempt);
+                    last_error = Error::UpstreamTimeout;
+                    continue 'targets;
+                }
+            }
+        }
+
+        attempts.push(attempt);
+        return Ok(Completion {
+            model: request.model,
+            stream: stream::iter(pending).chain(body).boxed_local(),
+            attempts,
+        });
+    }
+
+    console_log!("no upstream answered: {:?}", attempts);
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fallback() {
+        let attempt = |status: Option<u16>| Attempt {
+            provider: "openai".to_string(),
+            model: "gpt-3.5-turbo".to_string(),
+            status,
+            timed_out: false,
+        };
+        assert!(is_retryable(&attempt(Some(429))));
+        assert!(is_retryable(&attempt(Some(503))));
+        assert!(!is_retryable(&attempt(Some(400))));
+        assert!(!is_retryable(&attempt(Some(401))));
+        // the fetch itself failed, e.g. the connection was reset
+        assert!(is_retryable(&attempt(None)));
+    }
+}
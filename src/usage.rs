@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use std::ops::Add;
+
+use serde::{Deserialize, Serialize};
+use worker::kv::KvStore;
+
+use crate::error::Result;
+use crate::id;
+use crate::prompt::Message;
+
+/// Tokens spent on one answer.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TokenUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    /// whether the counts are our own estimate rather than reported by the upstream
+    #[serde(default)]
+    pub estimated: bool,
+}
+
+impl Add for TokenUsage {
+    type Output = TokenUsage;
+
+    fn add(self, other: TokenUsage) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens: self.prompt_tokens + other.prompt_tokens,
+            completion_tokens: self.completion_tokens + other.completion_tokens,
+            estimated: self.estimated || other.estimated,
+        }
+    }
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x3040..=0x30ff // kana
+        | 0x3400..=0x4dbf // CJK extension A
+        | 0x4e00..=0x9fff // CJK unified ideographs
+        | 0xac00..=0xd7af // hangul
+        | 0xf900..=0xfaff // CJK compatibility ideographs
+        | 0xff00..=0xffef) // full width forms
+}
+
+/// Rough token count of `text`: about one token per CJK character, and one per four
+/// characters of anything else. Good enough for budgets when the upstream doesn't report usage.
+pub fn estimate_tokens(text: &str) -> u32 {
+    let (cjk, other) = text.chars().fold((0u32, 0u32), |(cjk, other), c| {
+        if is_cjk(c) {
+            (cjk + 1, other)
+        } else {
+            (cjk, other + 1)
+        }
+    });
+    cjk + other.div_ceil(4)
+}
+
+/// Rough token count of a prompt. Each message costs a few tokens on top of its content.
+pub fn estimate_prompt_tokens(messages: &[Message]) -> u32 {
+    const TOKENS_PER_MESSAGE: u32 = 4;
+    messages
+        .iter()
+        .map(|m| estimate_tokens(&m.content) + TOKENS_PER_MESSAGE)
+        .sum()
+}
+
+/// Price of a model, in USD per thousand tokens.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Price {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+/// Daily spending limits. Once one is reached, chats are refused with `message` until UTC
+/// midnight.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct BudgetConfig {
+    pub daily_usd: Option<f64>,
+    /// prompt and completion tokens together
+    pub daily_tokens: Option<u64>,
+    /// keyed by model name; models without a price are counted as free. Sorted, so that
+    /// `Config::hash` is stable
+    #[serde(default)]
+    pub prices: BTreeMap<String, Price>,
+    /// shown to visitors once the budget is used up
+    pub message: Option<String>,
+}
+
+impl BudgetConfig {
+    pub fn has_limits(&self) -> bool {
+        self.daily_usd.is_some() || self.daily_tokens.is_some()
+    }
+
+    pub fn cost_usd(&self, model: &str, usage: &TokenUsage) -> f64 {
+        match self.prices.get(model) {
+            Some(price) => {
+                (usage.prompt_tokens as f64 * price.prompt
+                    + usage.completion_tokens as f64 * price.completion)
+                    / 1000.
+            }
+            None => 0.,
+        }
+    }
+
+    pub fn is_exhausted(&self, usage: &DailyUsage) -> bool {
+        let over_usd = self.daily_usd.is_some_and(|limit| usage.cost_usd >= limit);
+        let over_tokens = self
+            .daily_tokens
+            .is_some_and(|limit| usage.prompt_tokens + usage.completion_tokens >= limit);
+        over_usd || over_tokens
+    }
+
+    pub fn exhausted_message(&self) -> String {
+        self.message.clone().unwrap_or_else(|| {
+            "Salieri has answered a lot of questions today and is taking a rest. Please come back tomorrow!".to_string()
+        })
+    }
+}
+
+/// Chats answered across all visitors during one UTC day.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct DailyUsage {
+    pub requests: u64,
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default, alias = "tokens")]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub cost_usd: f64,
+    /// answers cut short because the upstream was too slow
+    #[serde(default)]
+    pub timeouts: u64,
+}
+
+fn usage_key(date: &str) -> String {
+    format!("usage_{}", date)
+}
+
+pub async fn get_daily_usage(kv: &KvStore, date: &str) -> Result<DailyUsage> {
+    Ok(kv
+        .get(&usage_key(date))
+        .json::<DailyUsage>()
+        .await?
+        .unwrap_or_default())
+}
+
+pub async fn get_today_usage(kv: &KvStore) -> Result<DailyUsage> {
+    get_daily_usage(kv, &id::get_utc_date()).await
+}
+
+/// Add one answered chat to today's usage. This is a read-modify-write on KV, so concurrent
+/// chats may undercount a little, which is fine for limits.
+pub async fn record_usage(
+    kv: &KvStore,
+    tokens: &TokenUsage,
+    cost_usd: f64,
+    timed_out: bool,
+) -> Result<()> {
+    let date = id::get_utc_date();
+    let mut usage = get_daily_usage(kv, &date).await?;
+    usage.requests += 1;
+    usage.prompt_tokens += tokens.prompt_tokens as u64;
+    usage.completion_tokens += tokens.completion_tokens as u64;
+    usage.cost_usd += cost_usd;
+    if timed_out {
+        usage.timeouts += 1;
+    }
+    kv.put(&usage_key(&date), &usage)?
+        // keep a few months of history around
+        .expiration_ttl(60 * 60 * 24 * 90)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("Hello, world"), 3);
+        assert_eq!(estimate_tokens("你好，世界"), 5);
+    }
+
+    #[test]
+    fn budget() {
+        let budget: BudgetConfig = serde_json::from_str(
+            r#"{"daily_usd": 1.0, "prices": {"gpt-3.5-turbo": {"prompt": 0.0015, "completion": 0.002}}}"#,
+        )
+        .unwrap();
+        let usage = TokenUsage {
+            prompt_tokens: 1000,
+            completion_tokens: 500,
+            estimated: false,
+        };
+        assert!((budget.cost_usd("gpt-3.5-turbo", &usage) - 0.0025).abs() < 1e-9);
+        assert_eq!(budget.cost_usd("unknown", &usage), 0.);
+
+        let mut daily = DailyUsage::default();
+        assert!(!budget.is_exhausted(&daily));
+        daily.cost_usd = 1.0;
+        assert!(budget.is_exhausted(&daily));
+    }
+}
@@ -1,4 +1,7 @@
 use cfg_if::cfg_if;
+use futures_util::future::{select, Either};
+use std::future::Future;
+use std::time::Duration;
 
 cfg_if! {
     // https://github.com/rustwasm/console_error_panic_hook#readme
@@ -10,3 +13,24 @@ cfg_if! {
         pub fn set_panic_hook() {}
     }
 }
+
+/// Await `future`, giving up after `millis` milliseconds. Without a limit, this is just `.await`.
+pub async fn timeout<F: Future>(future: F, millis: Option<u64>) -> Option<F::Output> {
+    let millis = match millis {
+        Some(millis) => millis,
+        None => return Some(future.await),
+    };
+    let delay = worker::Delay::from(Duration::from_millis(millis));
+    match select(Box::pin(future), Box::pin(delay)).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
+
+/// The stricter of two optional limits.
+pub fn earliest(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}